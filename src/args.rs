@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Result};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -15,6 +16,166 @@ pub struct Arguments {
     /// Show list of database files without defragging
     #[arg(long, global = true)]
     pub dry_run: bool,
+
+    /// Use quick_check instead of integrity_check before and after vacuum
+    #[arg(long, global = true)]
+    pub quick: bool,
+
+    /// Verify content of vacuumed database before replacing the original
+    #[arg(long, value_name = "MODE", global = true, value_enum, default_value_t = VerifyMode::Count)]
+    pub verify: VerifyMode,
+
+    /// How to vacuum a database
+    #[arg(long, value_name = "ENGINE", global = true, value_enum, default_value_t = Engine::Copy)]
+    pub engine: Engine,
+
+    /// Vacuum databases in place under an exclusive lock, same as --engine=in-place
+    #[arg(long, global = true, conflicts_with = "engine")]
+    pub in_place: bool,
+
+    /// Refresh statistics of query planner after vacuum
+    #[arg(long, value_name = "MODE", global = true, value_enum)]
+    pub statistics: Option<Statistics>,
+
+    /// Approximate number of rows examined in each index when refreshing statistics
+    #[arg(long, value_name = "ROWS", global = true, requires = "statistics")]
+    pub analysis_limit: Option<u32>,
+
+    /// Merge segments of full-text search indexes before vacuum
+    #[arg(long, value_name = "MODE", global = true, value_enum)]
+    pub fts: Option<FtsMode>,
+
+    /// Remove orphans and fix broken bookmark parents of Firefox's places.sqlite and favicons.sqlite
+    #[arg(long, global = true)]
+    pub places_maintenance: bool,
+
+    /// Remove rows of Chromium's History, Favicons, Top Sites, Shortcuts and Network Action Predictor which refer to expired visits or URLs
+    #[arg(long, global = true)]
+    pub history_maintenance: bool,
+
+    /// Remove expired cookies of Firefox and Chromium, session cookies are kept
+    #[arg(long, global = true)]
+    pub purge_expired_cookies: bool,
+
+    /// Remove visits older than this number of days from Firefox's and Chromium's history
+    #[arg(long, value_name = "DAYS", global = true)]
+    pub keep_history_days: Option<u32>,
+
+    /// Keep only this number of most recent visits in Firefox's and Chromium's history
+    #[arg(long, value_name = "VISITS", global = true)]
+    pub max_history_entries: Option<u32>,
+
+    /// Compact Firefox's session store by trimming closed tabs, closed windows and tab history, and remove stale session backups
+    #[arg(long, global = true)]
+    pub compact_sessions: bool,
+
+    /// Closed tabs kept in each window by `--compact-sessions`
+    #[arg(
+        long,
+        value_name = "TABS",
+        global = true,
+        default_value_t = 10,
+        requires = "compact_sessions"
+    )]
+    pub max_closed_tabs: usize,
+
+    /// Closed windows kept by `--compact-sessions`
+    #[arg(
+        long,
+        value_name = "WINDOWS",
+        global = true,
+        default_value_t = 3,
+        requires = "compact_sessions"
+    )]
+    pub max_closed_windows: usize,
+
+    /// Back and forward history entries kept in each tab by `--compact-sessions`
+    #[arg(
+        long,
+        value_name = "ENTRIES",
+        global = true,
+        default_value_t = 10,
+        requires = "compact_sessions"
+    )]
+    pub max_tab_history: usize,
+
+    /// Remove site data of origins which are neither visited nor touched within this number of days, origins with persistent storage are kept
+    #[arg(long, value_name = "DAYS", global = true)]
+    pub purge_stale_site_data: Option<u32>,
+
+    /// Change auto-vacuum mode of databases, so browsers can release free pages themselves
+    #[arg(long, value_name = "MODE", global = true, value_enum)]
+    pub set_auto_vacuum: Option<AutoVacuum>,
+
+    /// Change page size of databases during vacuum, `auto` for the browser's default of known databases
+    #[arg(long, value_name = "SIZE", global = true)]
+    pub page_size: Option<PageSize>,
+
+    /// Skip databases whose ratio of free pages is below this, e.g. 0.1, read from their header
+    #[arg(long, value_name = "RATIO", global = true, value_parser = parse_ratio)]
+    pub min_free_ratio: Option<f64>,
+
+    /// Skip databases whose free pages are smaller than this number of bytes
+    #[arg(long, value_name = "BYTES", global = true)]
+    pub min_reclaim: Option<u64>,
+
+    /// Keep original databases in $XDG_STATE_HOME/browser-defrag/backups
+    #[arg(long, global = true)]
+    pub backup: bool,
+
+    /// Number of backup runs of the browser to keep
+    #[arg(long, value_name = "COUNT", global = true, default_value_t = 5)]
+    pub backup_keep: usize,
+
+    /// Remove backup runs older than this number of days
+    #[arg(long, value_name = "DAYS", global = true)]
+    pub backup_max_age: Option<u64>,
+
+    /// Directory for database copies and SQLite's temporary files, default to TMPDIR
+    #[arg(long, value_name = "PATH", global = true)]
+    pub work_dir: Option<PathBuf>,
+
+    /// Give up defragging a database after this number of seconds and continue with the next
+    #[arg(long, value_name = "SECONDS", global = true)]
+    pub db_timeout: Option<u64>,
+
+    /// Continue with remaining databases of an interrupted run
+    #[arg(long, global = true)]
+    pub resume: bool,
+
+    /// Wait for the browser to exit instead of giving up, forever if SECONDS is not given
+    #[arg(long, value_name = "SECONDS", global = true, num_args = 0..=1, require_equals = true)]
+    pub wait: Option<Option<u64>>,
+
+    /// Ask a running browser to close with SIGTERM and wait until it has saved its session
+    #[arg(long, global = true)]
+    pub close: bool,
+
+    /// Launch the browser closed by --close again after defragging
+    #[arg(long, global = true, requires = "close")]
+    pub relaunch: bool,
+
+    /// Defrag profiles on network filesystems, e.g. NFS or SMB, where SQLite's locking is unreliable
+    #[arg(long, global = true)]
+    pub allow_network_fs: bool,
+
+    /// Rebuild corrupted databases from their readable rows, asks before replacing
+    #[arg(long, global = true, requires = "backup")]
+    pub salvage: bool,
+}
+
+/// Options which `restore` uses, others only apply to defragging
+const RESTORE_OPTIONS: [&str; 2] = ["dry_run", "quick"];
+
+/// Options given on the command line which have no meaning for `restore`
+pub fn restore_ignored(matches: &ArgMatches) -> Vec<String> {
+    Arguments::command()
+        .get_arguments()
+        .map(|arg| arg.get_id().as_str())
+        .filter(|id| !RESTORE_OPTIONS.contains(id))
+        .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+        .map(|id| format!("--{}", id.replace('_', "-")))
+        .collect()
 }
 
 #[derive(Debug, Subcommand)]
@@ -31,4 +192,159 @@ pub enum BrowserType {
         #[arg(long, value_name = "PATH", required = true)]
         profile_path: PathBuf,
     },
+
+    #[command(about = "Restore original databases from a backup")]
+    Restore {
+        /// Backup to restore, the latest backup if not given
+        #[arg(value_name = "RUN_ID")]
+        run_id: Option<String>,
+
+        /// Restore only databases of a profile, given by path or directory name
+        #[arg(long, value_name = "PROFILE")]
+        profile: Option<PathBuf>,
+
+        /// Restore only databases whose path ends with this, e.g. places.sqlite
+        #[arg(long, value_name = "DB")]
+        db: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum VerifyMode {
+    /// Compare schema, user_version, application_id and row count of every table
+    #[default]
+    Count,
+
+    /// Also compare ordered content hash of every table
+    Hash,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Engine {
+    /// Copy database to work directory, then vacuum the copy
+    #[default]
+    Copy,
+
+    /// Write a compact copy next to the original with VACUUM INTO, no full copy is needed
+    VacuumInto,
+
+    /// Vacuum the original under an exclusive lock, SQLite's rollback journal keeps it crash safe
+    InPlace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Statistics {
+    /// Run ANALYZE on every table and index
+    Analyze,
+
+    /// Run PRAGMA optimize, which only analyzes tables that may benefit from it
+    Optimize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum FtsMode {
+    /// Merge all segments of each index into one
+    Optimize,
+
+    /// Merge segments of the same level, faster than optimize on large indexes
+    Merge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum AutoVacuum {
+    /// Free pages are only released by VACUUM
+    None,
+
+    /// Free pages are released on every commit
+    Full,
+
+    /// Free pages are released by PRAGMA incremental_vacuum, e.g. by Firefox on idle
+    Incremental,
+}
+
+impl AutoVacuum {
+    /// Value of `PRAGMA auto_vacuum`
+    pub fn pragma(&self) -> &'static str {
+        match self {
+            AutoVacuum::None => "NONE",
+            AutoVacuum::Full => "FULL",
+            AutoVacuum::Incremental => "INCREMENTAL",
+        }
+    }
+}
+
+/// Page size policy of `--page-size`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageSize {
+    /// Default page size of the browser for databases in the catalog, others are kept
+    Auto,
+
+    /// Page size in bytes, a power of two from 512 to 65536
+    Bytes(u32),
+}
+
+impl FromStr for PageSize {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if value.eq_ignore_ascii_case("auto") {
+            return Ok(PageSize::Auto);
+        }
+
+        let Ok(size) = value.parse::<u32>() else {
+            bail!("expected `auto` or a number of bytes, e.g. 4096");
+        };
+        if !(512..=65536).contains(&size) || !size.is_power_of_two() {
+            bail!("page size must be a power of two from 512 to 65536");
+        }
+        Ok(PageSize::Bytes(size))
+    }
+}
+
+/// Parse a ratio from 0 to 1
+fn parse_ratio(value: &str) -> Result<f64> {
+    let Ok(ratio) = value.parse::<f64>() else {
+        bail!("expected a number from 0 to 1, e.g. 0.1");
+    };
+    if !(0.0..=1.0).contains(&ratio) {
+        bail!("ratio must be from 0 to 1");
+    }
+    Ok(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_restore_ignored() {
+        let matches = Arguments::command()
+            .try_get_matches_from(["browser-defrag", "restore", "--dry-run", "--quick"])
+            .unwrap();
+        assert_eq!(restore_ignored(&matches), Vec::<String>::new());
+
+        let matches = Arguments::command()
+            .try_get_matches_from([
+                "browser-defrag",
+                "--backup",
+                "restore",
+                "--set-auto-vacuum=full",
+            ])
+            .unwrap();
+        assert_eq!(
+            restore_ignored(&matches),
+            vec!["--set-auto-vacuum", "--backup"]
+        );
+    }
+
+    #[test]
+    fn test_parse_page_size() {
+        assert_eq!("auto".parse::<PageSize>().unwrap(), PageSize::Auto);
+        assert_eq!("32768".parse::<PageSize>().unwrap(), PageSize::Bytes(32768));
+        assert!("3000".parse::<PageSize>().is_err());
+        assert!("256".parse::<PageSize>().is_err());
+        assert!("131072".parse::<PageSize>().is_err());
+        assert!("big".parse::<PageSize>().is_err());
+    }
 }
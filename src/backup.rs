@@ -0,0 +1,401 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use tempfile::tempdir;
+use tracing::{debug, error, info};
+
+use crate::{
+    common::{
+        format_timestamp, is_sqlite3_file, replace_file, sidecar_path, state_dir, SIDECAR_SUFFIXES,
+    },
+    defrag::is_running,
+    integrity::integrity_check,
+    mozlz4,
+};
+
+/// Name of file which lists original path of every database in a backup run
+const MANIFEST: &str = "manifest";
+
+/// Backups of original databases of a single run
+///
+/// Each run is a directory `<RUN_ID>` under `$XDG_STATE_HOME/browser-defrag/backups`,
+/// where `<RUN_ID>` is `<YYYYMMDDTHHMMSS>-<Browser>`, or `<YYYYMMDDTHHMMSS>.<N>-<Browser>`
+/// if another run started in the same second.
+/// An original database and its sidecar files are kept at their absolute path below `files/`.
+#[derive(Debug)]
+pub struct Backup {
+    pub root: PathBuf,
+    pub run_id: String,
+
+    /// Number of runs to keep
+    pub keep: usize,
+
+    /// Remove runs older than this
+    pub max_age: Option<Duration>,
+}
+
+impl Backup {
+    pub fn new(browser_name: &str, keep: usize, max_age: Option<Duration>) -> Result<Self> {
+        let root = backup_root()?;
+        let run_id = create_run_dir(&root, &format_timestamp(SystemTime::now()), browser_name)?;
+        Ok(Self {
+            root,
+            run_id,
+            keep,
+            max_age,
+        })
+    }
+
+    pub fn run_dir(&self) -> PathBuf {
+        self.root.join(&self.run_id)
+    }
+
+    /// Whether any file is kept in this run
+    pub fn has_files(&self) -> bool {
+        self.run_dir().join(MANIFEST).is_file()
+    }
+
+    /// Keep a copy of a database and its sidecar files
+    pub fn save(&self, path: &Path) -> Result<PathBuf> {
+        let run_dir = self.run_dir();
+        let target = backup_path(&run_dir, path);
+        fs::create_dir_all(target.parent().unwrap()).with_context(|| {
+            format!("Failed to create backup directory of `{}`", path.display())
+        })?;
+
+        fs::copy(path, &target)
+            .with_context(|| format!("Failed to backup `{}`", path.display()))?;
+        for suffix in SIDECAR_SUFFIXES {
+            let sidecar = sidecar_path(path, suffix);
+            if sidecar.exists() {
+                fs::copy(&sidecar, sidecar_path(&target, suffix))
+                    .with_context(|| format!("Failed to backup `{}`", sidecar.display()))?;
+            }
+        }
+
+        let mut manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(run_dir.join(MANIFEST))
+            .context("Failed to open backup manifest")?;
+        writeln!(manifest, "{}", path.display()).context("Failed to write backup manifest")?;
+
+        debug!("Backup `{}` to `{}`", path.display(), target.display());
+        Ok(target)
+    }
+
+    /// Remove old runs beyond retention, the current run is always kept
+    ///
+    /// Runs of other browsers are not counted, they have their own retention.
+    pub fn prune(&self) -> Result<()> {
+        let browser_name = run_browser_name(&self.run_id);
+        let mut runs: Vec<String> = list_runs(&self.root)?;
+        runs.retain(|run_id| run_id != &self.run_id && run_browser_name(run_id) == browser_name);
+        runs.reverse();
+
+        // Nothing was kept in this run
+        if !self.has_files() {
+            let _ = fs::remove_dir(self.run_dir());
+        }
+
+        let keep = self.keep.saturating_sub(usize::from(self.has_files()));
+        for (index, run_id) in runs.iter().enumerate() {
+            let run_dir = self.root.join(run_id);
+            let expired = match self.max_age {
+                Some(max_age) => run_dir
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > max_age)),
+                None => false,
+            };
+
+            if index >= keep || expired {
+                info!("Remove backup `{}`", run_dir.display());
+                fs::remove_dir_all(&run_dir)
+                    .with_context(|| format!("Failed to remove backup `{}`", run_dir.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Root directory of all backup runs
+pub fn backup_root() -> Result<PathBuf> {
+    Ok(state_dir()?.join("backups"))
+}
+
+/// Create directory of a new backup run and return its `<RUN_ID>`
+///
+/// Runs started in the same second get a sequence number, e.g. `20261019T101500.1-Firefox`,
+/// which sorts after the first run.
+fn create_run_dir(root: &Path, timestamp: &str, browser_name: &str) -> Result<String> {
+    fs::create_dir_all(root)
+        .with_context(|| format!("Failed to create backup directory `{}`", root.display()))?;
+
+    let mut sequence = 0;
+    loop {
+        let run_id = match sequence {
+            0 => format!("{timestamp}-{browser_name}"),
+            _ => format!("{timestamp}.{sequence}-{browser_name}"),
+        };
+        match fs::create_dir(root.join(&run_id)) {
+            Ok(()) => return Ok(run_id),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => sequence += 1,
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to create backup `{run_id}`"))
+            }
+        }
+    }
+}
+
+/// Location of a database inside a backup run
+fn backup_path(run_dir: &Path, path: &Path) -> PathBuf {
+    run_dir
+        .join("files")
+        .join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Browser of a backup run, from `<RUN_ID>` of `<YYYYMMDDTHHMMSS>-<Browser>`
+fn run_browser_name(run_id: &str) -> Option<&str> {
+    run_id.split_once('-').map(|(_, browser_name)| browser_name)
+}
+
+/// List backup runs, oldest first
+fn list_runs(root: &Path) -> Result<Vec<String>> {
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut runs: Vec<String> = fs::read_dir(root)
+        .with_context(|| format!("Failed to read backup directory `{}`", root.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join(MANIFEST).is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    runs.sort();
+
+    Ok(runs)
+}
+
+/// Put original databases of a backup run back
+///
+/// `run_id` - Backup run to restore, the latest run if not given
+///
+/// `profile` - Restore only databases under this profile directory, or profile directory name
+///
+/// `db` - Restore only databases whose path ends with this, e.g. `places.sqlite`
+///
+/// `dry_run` - Only list databases which would be restored
+pub fn restore(
+    run_id: Option<&str>,
+    profile: Option<&Path>,
+    db: Option<&Path>,
+    quick_check: bool,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>> {
+    let root = backup_root()?;
+    let run_id: String = match run_id {
+        Some(run_id) => run_id.to_string(),
+        None => match list_runs(&root)?.pop() {
+            Some(run_id) => run_id,
+            None => bail!("No backup found in `{}`", root.display()),
+        },
+    };
+
+    let run_dir = root.join(&run_id);
+    let manifest = fs::read_to_string(run_dir.join(MANIFEST))
+        .with_context(|| format!("Backup `{run_id}` does not exist"))?;
+
+    // Check if browser is running?
+    let Some(browser_name) = run_browser_name(&run_id) else {
+        bail!("Invalid backup `{run_id}`");
+    };
+    if !dry_run && is_running(browser_name) {
+        bail!("Cannot restore. `{browser_name}` is running!!!");
+    }
+
+    let mut restored: Vec<PathBuf> = Vec::new();
+    for path in manifest.lines().map(PathBuf::from) {
+        if let Some(profile) = profile {
+            let in_profile = path.starts_with(profile)
+                || path
+                    .ancestors()
+                    .any(|ancestor| ancestor.file_name() == Some(profile.as_os_str()));
+            if !in_profile {
+                continue;
+            }
+        }
+
+        if db.is_some_and(|db| !path.ends_with(db)) {
+            continue;
+        }
+
+        if dry_run {
+            restored.push(path);
+            continue;
+        }
+
+        match restore_database(&run_dir, &path, quick_check) {
+            Ok(()) => restored.push(path),
+            Err(err) => error!("{err:#}"),
+        }
+    }
+
+    Ok(restored)
+}
+
+/// Restore a database and its sidecar files from a backup run
+fn restore_database(run_dir: &Path, path: &Path, quick_check: bool) -> Result<()> {
+    let source = backup_path(run_dir, path);
+
+    // Session store of `--compact-sessions`
+    if mozlz4::is_mozlz4_file(&source)? {
+        let data = fs::read(&source)?;
+        mozlz4::decompress(&data).with_context(|| {
+            format!("Backup `{}` is corrupted, do not restore", source.display())
+        })?;
+        return replace_file(&source, path);
+    }
+
+    if !is_sqlite3_file(&source)? {
+        bail!("Backup `{}` is not a database file", source.display());
+    }
+
+    // Check backup on a copy, opening it may write to sidecar files
+    let tmp_dir = tempdir()?;
+    let source_copy = tmp_dir.path().join(source.file_name().unwrap());
+    fs::copy(&source, &source_copy)?;
+    for suffix in SIDECAR_SUFFIXES {
+        let sidecar = sidecar_path(&source, suffix);
+        if sidecar.exists() {
+            fs::copy(&sidecar, sidecar_path(&source_copy, suffix))?;
+        }
+    }
+
+    let connection = sqlite::open(&source_copy)
+        .with_context(|| format!("Failed to open backup `{}`", source.display()))?;
+    let problems = integrity_check(&connection, quick_check)?;
+    drop(connection);
+    if !problems.is_empty() {
+        bail!(
+            "Backup `{}` is corrupted, do not restore: {}",
+            source.display(),
+            problems.join("; ")
+        );
+    }
+
+    replace_file(&source, path)
+        .with_context(|| format!("Failed to restore `{}`", path.display()))?;
+
+    // Sidecar files of current database do not belong to the backup,
+    // they are removed once the current database is replaced
+    for suffix in SIDECAR_SUFFIXES {
+        let sidecar = sidecar_path(path, suffix);
+        if sidecar.exists() {
+            fs::remove_file(&sidecar)
+                .with_context(|| format!("Failed to remove `{}`", sidecar.display()))?;
+        }
+    }
+
+    for suffix in SIDECAR_SUFFIXES {
+        let sidecar = sidecar_path(&source, suffix);
+        if sidecar.exists() {
+            fs::copy(&sidecar, sidecar_path(path, suffix))
+                .with_context(|| format!("Failed to restore `{}`", sidecar.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_create_run_dir_in_same_second() {
+        let root = tempdir().unwrap();
+        let run_ids: Vec<String> = (0..3)
+            .map(|_| create_run_dir(root.path(), "20260101T000000", "Firefox").unwrap())
+            .collect();
+        assert_eq!(
+            run_ids,
+            vec![
+                "20260101T000000-Firefox",
+                "20260101T000000.1-Firefox",
+                "20260101T000000.2-Firefox",
+            ]
+        );
+        assert_eq!(run_browser_name(&run_ids[2]), Some("Firefox"));
+    }
+
+    #[test]
+    fn test_restore_database() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("places.sqlite");
+        let connection = sqlite::open(&path).unwrap();
+        connection
+            .execute("CREATE TABLE t (x); INSERT INTO t VALUES (1);")
+            .unwrap();
+        drop(connection);
+
+        let backup = Backup {
+            root: tmp_dir.path().join("backups"),
+            run_id: "20260101T000000-Firefox".to_string(),
+            keep: 1,
+            max_age: None,
+        };
+        backup.save(&path).unwrap();
+
+        fs::write(&path, "current").unwrap();
+        for suffix in SIDECAR_SUFFIXES {
+            fs::write(sidecar_path(&path, suffix), "current").unwrap();
+        }
+
+        restore_database(&backup.run_dir(), &path, true).unwrap();
+        for suffix in SIDECAR_SUFFIXES {
+            assert!(!sidecar_path(&path, suffix).exists());
+        }
+        let connection = sqlite::open(&path).unwrap();
+        let mut statement = connection.prepare("SELECT x FROM t").unwrap();
+        statement.next().unwrap();
+        assert_eq!(statement.read::<i64, _>(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_prune_runs_of_same_browser() {
+        let root = tempdir().unwrap();
+        for run_id in [
+            "20260101T000000-Firefox",
+            "20260102T000000-Chromium",
+            "20260103T000000-Chromium",
+            "20260104T000000-Firefox",
+        ] {
+            fs::create_dir(root.path().join(run_id)).unwrap();
+            fs::write(root.path().join(run_id).join(MANIFEST), "").unwrap();
+        }
+
+        let backup = Backup {
+            root: root.path().to_path_buf(),
+            run_id: "20260105T000000-Chromium".to_string(),
+            keep: 1,
+            max_age: None,
+        };
+        backup.prune().unwrap();
+        assert_eq!(
+            list_runs(root.path()).unwrap(),
+            vec![
+                "20260101T000000-Firefox",
+                "20260103T000000-Chromium",
+                "20260104T000000-Firefox",
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::path::Path;
+
+/// Browser engine which owns a database
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Family {
+    /// Firefox and its derivatives, e.g. Thunderbird, LibreWolf
+    Gecko,
+
+    /// Chromium and its derivatives
+    Chromium,
+}
+
+/// Database known to be created by a browser
+#[derive(Debug, PartialEq)]
+pub struct KnownDatabase {
+    pub family: Family,
+
+    /// File name of database
+    pub name: &'static str,
+
+    /// Page size of databases created by current versions of the browser, see `--page-size=auto`
+    pub page_size: u32,
+}
+
+impl KnownDatabase {
+    /// Check whether database keeps visits of the browser profile in its directory
+    pub fn has_visits(&self) -> bool {
+        matches!(
+            (self.family, self.name),
+            (Family::Gecko, "places.sqlite") | (Family::Chromium, "History")
+        )
+    }
+}
+
+/// Default page size of mozStorage since Firefox 58
+const GECKO_PAGE_SIZE: u32 = 32768;
+
+/// Default page size of Chromium's `sql::Database`
+const CHROMIUM_PAGE_SIZE: u32 = 4096;
+
+/// Catalog of known browser databases
+pub const KNOWN_DATABASES: &[KnownDatabase] = &[
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "places.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "favicons.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "cookies.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "formhistory.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "permissions.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "content-prefs.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "webappsstore.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "storage.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "protections.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "credentialstate.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "storage-sync-v2.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Gecko,
+        name: "global-messages-db.sqlite",
+        page_size: GECKO_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "History",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "Favicons",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "Cookies",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "Top Sites",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "Shortcuts",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "Network Action Predictor",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "Web Data",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "Login Data",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+    KnownDatabase {
+        family: Family::Chromium,
+        name: "Extension Cookies",
+        page_size: CHROMIUM_PAGE_SIZE,
+    },
+];
+
+/// Find a database in catalog by its file name
+pub fn find(path: &Path) -> Option<&'static KnownDatabase> {
+    let name = path.file_name()?.to_str()?;
+    KNOWN_DATABASES.iter().find(|known| known.name == name)
+}
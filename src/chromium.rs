@@ -24,12 +24,15 @@ pub fn list_db(config: Config) -> Result<Vec<Profile>> {
     let database_lists: Vec<Profile> = vec![Profile {
         name: "".to_string(),
         path: profile_path,
+        filesystem: None,
         databases: Some(
             database_files
                 .into_iter()
                 .map(|path| Database::new(&path))
                 .collect::<Vec<Database>>(),
         ),
+        sessions: None,
+        site_data: None,
     }];
 
     Ok(database_lists)
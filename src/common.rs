@@ -1,49 +1,82 @@
 use std::{
-    fs::File,
-    io::Read,
+    env,
+    ffi::{CString, OsString},
+    fs::{self, File},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result};
-use tracing::{debug, trace};
+use anyhow::{bail, Context, Result};
+use tracing::debug;
 use walkdir::WalkDir;
 
+use crate::header::{self, Header, MAGIC};
+
+/// State of a database file used to detect modification by other processes
+#[derive(Debug, PartialEq)]
+pub struct FileState {
+    pub inode: u64,
+    pub size: u64,
+    pub modified: SystemTime,
+    pub change_counter: u32,
+
+    /// Size and modification time of write-ahead log, if any
+    pub wal: Option<(u64, SystemTime)>,
+}
+
+impl FileState {
+    pub fn read(path: &Path) -> Result<Self> {
+        let metadata = path
+            .metadata()
+            .with_context(|| format!("Could not read metadata of `{}`", path.display()))?;
+
+        let wal = sidecar_path(path, "-wal")
+            .metadata()
+            .ok()
+            .map(|wal| wal.modified().map(|modified| (wal.len(), modified)))
+            .transpose()?;
+
+        Ok(Self {
+            inode: metadata.ino(),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            change_counter: Header::read(path)?.change_counter,
+            wal,
+        })
+    }
+
+    /// Check whether a file was changed since its state was read
+    ///
+    /// An empty WAL created since then is no change, a read-only connection of a WAL database
+    /// creates it.
+    pub fn is_changed(&self, path: &Path) -> Result<bool> {
+        let mut current = Self::read(path)?;
+        if self.wal.is_none() && current.wal.is_some_and(|(size, _)| size == 0) {
+            current.wal = None;
+        }
+
+        Ok(current != *self)
+    }
+}
+
+/// Suffixes of sidecar files which belong to a database
+pub const SIDECAR_SUFFIXES: [&str; 3] = ["-wal", "-shm", "-journal"];
+
+/// Path of a database sidecar file, e.g. `-wal`, `-shm` or `-journal`
+pub fn sidecar_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut sidecar: OsString = path.as_os_str().to_os_string();
+    sidecar.push(suffix);
+    PathBuf::from(sidecar)
+}
+
 /// Check whether a file is valid sqlite3 or not.
 ///
-/// Check whether first 16 bytes of a file contains "SQLite format 3\000" or not.
+/// Check whether the header of a file starts with "SQLite format 3\000" or not.
 ///
 /// See: https://www.sqlite.org/fileformat.html
 pub fn is_sqlite3_file(path: &Path) -> Result<bool> {
-    let header_size: usize = 16;
-    let mut header: Vec<u8> = Vec::with_capacity(header_size);
-
-    let mut file =
-        File::open(path).with_context(|| format!("Could not open `{}`", path.display()))?;
-
-    file.by_ref()
-        .take(header_size as u64)
-        .read_to_end(&mut header)
-        .with_context(|| format!("Could not read header string of `{}`", path.display()))?;
-
-    match std::str::from_utf8(&header).with_context(|| {
-        format!(
-            "Failed to convert string header `{:?}` of `{}` to UTF8",
-            header,
-            path.display()
-        )
-    }) {
-        Ok(h) => {
-            if h == "SQLite format 3\x00" {
-                return Ok(true);
-            }
-        }
-        Err(err) => {
-            trace!("{err:#}");
-            return Ok(false);
-        }
-    }
-
-    Ok(false)
+    Ok(header::read_bytes(path)?.starts_with(MAGIC))
 }
 
 /// Find all sqlite3 files in `root`
@@ -65,3 +98,165 @@ pub fn find_sqlite3_files(root: &Path, max_depth: usize) -> Result<Vec<PathBuf>>
 
     Ok(database_files)
 }
+
+/// Quote an SQL identifier, e.g. a table name, with double quotes
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Path of staging file which replaces a database atomically
+pub fn staging_path(path: &Path) -> PathBuf {
+    sidecar_path(path, ".browser-defrag")
+}
+
+/// Replace `target` with a copy of `source`
+///
+/// The copy is written to a staging file next to `target` and then renamed over it,
+/// so an interruption never leaves a partially written `target`.
+/// `source` must include the content of the original's WAL, which is removed.
+pub fn replace_file(source: &Path, target: &Path) -> Result<()> {
+    write_staging(source, target)?;
+    install_staging(target)
+}
+
+/// Write a copy of `source` to the staging file of `target`
+pub fn write_staging(source: &Path, target: &Path) -> Result<()> {
+    let staging = staging_path(target);
+    fs::copy(source, &staging)
+        .and_then(|_| File::open(&staging)?.sync_all())
+        .with_context(|| format!("Failed to write `{}`", staging.display()))?;
+
+    Ok(())
+}
+
+/// Replace `target` with its staging file, e.g. written by `VACUUM INTO`
+///
+/// The database is rebuilt from the original and its WAL.
+pub fn install_staging(target: &Path) -> Result<()> {
+    let staging = staging_path(target);
+    File::open(&staging)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Failed to write `{}`", staging.display()))?;
+
+    if let Err(err) = fs::rename(&staging, target) {
+        let _ = fs::remove_file(&staging);
+        return Err(err).with_context(|| format!("Failed to replace `{}`", target.display()));
+    }
+
+    remove_wal(target)
+}
+
+/// Remove WAL and shared-memory files of a replaced database, they must not be replayed onto it
+pub fn remove_wal(target: &Path) -> Result<()> {
+    for suffix in ["-wal", "-shm"] {
+        let sidecar = sidecar_path(target, suffix);
+        if sidecar.exists() {
+            fs::remove_file(&sidecar)
+                .with_context(|| format!("Failed to remove `{}`", sidecar.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Available space in bytes of filesystem containing `path`
+pub fn available_space(path: &Path) -> Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        bail!(
+            "Could not read filesystem statistics of `{}`: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Check whether two paths are on the same filesystem
+pub fn is_same_filesystem(path: &Path, other: &Path) -> bool {
+    match (path.metadata(), other.metadata()) {
+        (Ok(metadata), Ok(other_metadata)) => metadata.dev() == other_metadata.dev(),
+        _ => false,
+    }
+}
+
+/// Directory of browser-defrag's state at `$XDG_STATE_HOME/browser-defrag`
+pub fn state_dir() -> Result<PathBuf> {
+    let state_root: PathBuf = match env::var("XDG_STATE_HOME") {
+        Ok(var) => PathBuf::from(var),
+        Err(_) => PathBuf::from(env::var("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+
+    Ok(state_root.join("browser-defrag"))
+}
+
+/// Format a time as `YYYYMMDDTHHMMSS` in UTC
+pub fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Convert days since epoch to civil date
+    // See: https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "19700101T000000");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(951782400 + 3723)),
+            "20000229T010203"
+        );
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(1792368000)),
+            "20261019T000000"
+        );
+    }
+
+    #[test]
+    fn test_replace_file_removes_wal() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source = tmp_dir.path().join("source.sqlite");
+        let target = tmp_dir.path().join("target.sqlite");
+        fs::write(&source, "new").unwrap();
+        fs::write(&target, "old").unwrap();
+        for suffix in ["-wal", "-shm"] {
+            fs::write(sidecar_path(&target, suffix), "old").unwrap();
+        }
+
+        replace_file(&source, &target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert!(!sidecar_path(&target, "-wal").exists());
+        assert!(!sidecar_path(&target, "-shm").exists());
+        assert!(!staging_path(&target).exists());
+    }
+}
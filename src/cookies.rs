@@ -0,0 +1,116 @@
+use anyhow::Result;
+use sqlite::Connection;
+
+use crate::{
+    catalog::{Family, KnownDatabase},
+    maintenance::{self, Cleanup, Task},
+};
+
+/// Tasks of Firefox's `cookies.sqlite`, whose session cookies are never stored
+///
+/// `expiry` is in seconds since Unix epoch, milliseconds are also accepted in case it changes.
+const GECKO_TASKS: &[Task] = &[Task {
+    table: "moz_cookies",
+    action: "removed",
+    requires: &["moz_cookies.expiry"],
+    sql: "DELETE FROM moz_cookies WHERE
+            CASE WHEN expiry > 100000000000 THEN expiry / 1000 ELSE expiry END
+            < CAST(strftime('%s', 'now') AS INTEGER);",
+}];
+
+/// Tasks of Chromium's `Cookies`
+///
+/// `expires_utc` is in microseconds since 1601-01-01 (WebKit epoch), 0 for session cookies.
+const CHROMIUM_TASKS: &[Task] = &[Task {
+    table: "cookies",
+    action: "removed",
+    requires: &["cookies.expires_utc"],
+    sql: "DELETE FROM cookies WHERE expires_utc <> 0
+            AND expires_utc < (CAST(strftime('%s', 'now') AS INTEGER) + 11644473600) * 1000000;",
+}];
+
+/// Check whether a database is cleaned up by `--purge-expired-cookies`
+pub fn is_cookies(known: &KnownDatabase) -> bool {
+    matches!(
+        known.name,
+        "cookies.sqlite" | "Cookies" | "Extension Cookies"
+    )
+}
+
+/// Remove expired cookies and keep session cookies, in one transaction
+pub fn purge_expired(connection: &Connection, known: &KnownDatabase) -> Result<Vec<Cleanup>> {
+    let tasks: &[Task] = match known.family {
+        Family::Gecko => GECKO_TASKS,
+        Family::Chromium => CHROMIUM_TASKS,
+    };
+
+    maintenance::run(connection, tasks, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog;
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    #[test]
+    fn test_purge_expired_gecko_cookies() {
+        let connection = sqlite::open(":memory:").unwrap();
+        // Expired in 2020 and expires in 2200, in seconds and in milliseconds
+        connection
+            .execute(
+                "CREATE TABLE moz_cookies (name TEXT, expiry INTEGER);
+                 INSERT INTO moz_cookies VALUES
+                   ('expired', 1577836800),
+                   ('valid', 7258118400),
+                   ('expired_ms', 1577836800000),
+                   ('valid_ms', 7258118400000);",
+            )
+            .unwrap();
+
+        let known = catalog::find(Path::new("cookies.sqlite")).unwrap();
+        assert_eq!(
+            purge_expired(&connection, known).unwrap(),
+            vec![Cleanup {
+                table: "moz_cookies",
+                action: "removed",
+                rows: 2
+            }]
+        );
+
+        let mut statement = connection
+            .prepare("SELECT name FROM moz_cookies ORDER BY name;")
+            .unwrap();
+        let mut names: Vec<String> = Vec::new();
+        while let sqlite::State::Row = statement.next().unwrap() {
+            names.push(statement.read::<String, _>(0).unwrap());
+        }
+        assert_eq!(names, vec!["valid", "valid_ms"]);
+    }
+
+    #[test]
+    fn test_purge_expired_chromium_cookies() {
+        let connection = sqlite::open(":memory:").unwrap();
+        // Expired in 2020, expires in 2200 and a session cookie
+        connection
+            .execute(
+                "CREATE TABLE cookies (name TEXT, expires_utc INTEGER);
+                 INSERT INTO cookies VALUES
+                   ('expired', 13222310400000000),
+                   ('valid', 18902592000000000),
+                   ('session', 0);",
+            )
+            .unwrap();
+
+        let known = catalog::find(Path::new("Cookies")).unwrap();
+        assert_eq!(
+            purge_expired(&connection, known).unwrap(),
+            vec![Cleanup {
+                table: "cookies",
+                action: "removed",
+                rows: 1
+            }]
+        );
+    }
+}
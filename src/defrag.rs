@@ -1,24 +1,65 @@
 use std::{
-    fs,
+    env, fs,
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context, Result};
+use humansize::{format_size, BINARY};
+use sqlite::{Connection, OpenFlags};
 use sysinfo::{ProcessesToUpdate, System};
-use tempfile::tempdir;
-use tracing::error;
+use tempfile::{tempdir_in, TempDir};
+use tracing::{error, info, warn};
+
+use crate::{
+    args::{AutoVacuum, Engine, FtsMode, PageSize, Statistics, VerifyMode},
+    backup::Backup,
+    catalog::{self, KnownDatabase},
+    common::{
+        available_space, install_staging, is_same_filesystem, remove_wal, sidecar_path,
+        staging_path, write_staging, FileState,
+    },
+    cookies,
+    filesystem::{check_replaceable, FileSystem},
+    fts, functions,
+    header::Header,
+    history,
+    integrity::{integrity_check, is_corrupt, Signature},
+    interrupt::is_interrupted,
+    journal::Journal,
+    maintenance::{Cleanup, Retention},
+    places,
+    report::ReportOptions,
+    salvage,
+    session::{self, SessionFile, SessionLimits},
+    site_data::{self, SiteData},
+    timeout::{set_deadline, BUSY_TIMEOUT},
+};
 
 #[derive(Debug)]
 pub struct Browser {
     pub name: String,
     pub database_lists: Option<Vec<Profile>>,
+
+    /// Options of the run shown in the report, set by defrag
+    pub report: ReportOptions,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Profile {
     pub name: String,
     pub path: PathBuf,
+
+    /// Type of filesystem of profile, e.g. `btrfs`
+    pub filesystem: Option<String>,
+
     pub databases: Option<Vec<Database>>,
+
+    /// Session store files, see `--compact-sessions`
+    pub sessions: Option<Vec<SessionFile>>,
+
+    /// Stale site data, see `--purge-stale-site-data`
+    pub site_data: Option<Vec<SiteData>>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -26,7 +67,65 @@ pub struct Database {
     pub path: PathBuf,
     pub size_before: Option<u64>,
     pub size_after: Option<u64>,
-    pub defrag: bool,
+    pub status: Status,
+
+    /// Entry of catalog if database is known to be created by a browser
+    pub known: Option<&'static KnownDatabase>,
+
+    /// Statistics of query planner have been refreshed, see `--statistics`
+    pub statistics_refreshed: bool,
+
+    /// Auto-vacuum mode before and after defrag, see `--set-auto-vacuum`
+    pub auto_vacuum: Option<(AutoVacuum, AutoVacuum)>,
+
+    /// Page size before and after defrag, see `--page-size`
+    pub page_size: Option<(u32, u32)>,
+
+    /// Size of FTS shadow tables before and after defrag, see `--fts`
+    pub fts_size: Option<(u64, u64)>,
+
+    /// Rows changed by maintenance, e.g. `--places-maintenance` or `--purge-expired-cookies`
+    pub cleanups: Option<Vec<Cleanup>>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub enum Status {
+    /// Database has not been defragged
+    #[default]
+    NotDefragged,
+
+    /// Database has been vacuumed and reindexed
+    Defragged,
+
+    /// Database did not pass integrity check before defragging
+    Corrupted,
+
+    /// Vacuumed copy of database did not pass integrity check
+    CheckFailed,
+
+    /// Content of vacuumed copy of database differs from the original
+    VerifyFailed,
+
+    /// Original database was modified by other process while defragging
+    Modified,
+
+    /// Not enough space in work directory to defrag database
+    NoSpace,
+
+    /// Database has been finished by a previous run, see `--resume`
+    PreviouslyDone,
+
+    /// Corrupted database has been rebuilt from its readable rows, see `--salvage`
+    Salvaged,
+
+    /// Database cannot be defragged safely, e.g. `requires tokenizer mozporter`
+    Skipped(String),
+
+    /// Defragging database took longer than `--db-timeout`
+    TimedOut,
+
+    /// Database has too few free pages, see `--min-free-ratio` and `--min-reclaim`
+    NotFragmented,
 }
 
 #[derive(Debug)]
@@ -35,9 +134,81 @@ pub struct Config {
     pub profile_path: Option<PathBuf>,
 }
 
+#[derive(Debug, Default)]
+pub struct DefragOptions {
+    /// Show list of database files without defragging
+    pub dry_run: bool,
+
+    /// Use `PRAGMA quick_check` instead of `PRAGMA integrity_check`
+    pub quick_check: bool,
+
+    /// How to compare content of database before and after vacuum
+    pub verify: VerifyMode,
+
+    /// How to vacuum a database
+    pub engine: Engine,
+
+    /// How to refresh statistics of query planner after vacuum
+    pub statistics: Option<Statistics>,
+
+    /// Approximate number of rows examined in each index by `ANALYZE`
+    pub analysis_limit: Option<u32>,
+
+    /// Merge segments of FTS indexes
+    pub fts: Option<FtsMode>,
+
+    /// Remove orphans of Firefox's Places databases
+    pub places_maintenance: bool,
+
+    /// Remove rows of Chromium's databases which refer to expired history
+    pub history_maintenance: bool,
+
+    /// Remove expired cookies of both browser families
+    pub purge_expired_cookies: bool,
+
+    /// Limits of history, pruned with maintenance of its database
+    pub retention: Retention,
+
+    /// Limits of Firefox's session store, it is compacted if set
+    pub sessions: Option<SessionLimits>,
+
+    /// Remove data of origins which are not visited or touched within this number of days
+    pub purge_stale_site_data: Option<u32>,
+
+    /// Change auto-vacuum mode of databases
+    pub auto_vacuum: Option<AutoVacuum>,
+
+    /// Change page size of databases
+    pub page_size: Option<PageSize>,
+
+    /// Skip databases whose ratio of free pages is below this
+    pub min_free_ratio: Option<f64>,
+
+    /// Skip databases whose free pages are smaller than this number of bytes
+    pub min_reclaim: Option<u64>,
+
+    /// Keep original databases in a backup run
+    pub backup: Option<Backup>,
+
+    /// Directory of database copies and SQLite's temporary files, default to `TMPDIR`
+    pub work_dir: Option<PathBuf>,
+
+    /// Journal of the run, to resume or clean up after interruption
+    pub journal: Option<Journal>,
+
+    /// Rebuild corrupted databases from their readable rows
+    pub salvage: bool,
+
+    /// Defrag profiles on network filesystems, e.g. NFS or SMB
+    pub allow_network_fs: bool,
+
+    /// Give up defragging a database after this duration
+    pub db_timeout: Option<Duration>,
+}
+
 pub trait Defragment {
     /// Vacuum and reindex databases
-    fn defrag(&mut self, dry_run: bool) -> Result<()>;
+    fn defrag(&mut self, options: &DefragOptions) -> Result<()>;
 }
 
 impl Browser {
@@ -45,6 +216,7 @@ impl Browser {
         Self {
             name: name.to_string(),
             database_lists: None,
+            report: ReportOptions::default(),
         }
     }
 
@@ -66,25 +238,122 @@ impl Database {
             path: path.to_path_buf(),
             size_before: None,
             size_after: None,
-            defrag: false,
+            status: Status::default(),
+            known: catalog::find(path),
+            statistics_refreshed: false,
+            auto_vacuum: None,
+            page_size: None,
+            fts_size: None,
+            cleanups: None,
         }
     }
 
+    /// Size of database file and its write-ahead log
     pub fn database_size(&self) -> Result<u64> {
-        Ok(self.path.metadata()?.len())
+        let wal_size: u64 = sidecar_path(&self.path, "-wal")
+            .metadata()
+            .map_or(0, |wal| wal.len());
+        Ok(self.path.metadata()?.len() + wal_size)
+    }
+
+    /// Replace a corrupted database with a database rebuilt from its readable rows
+    ///
+    /// The original is replaced only if every readable row is kept, the user confirms it
+    /// and the original is kept in a backup.
+    ///
+    /// `source` - Corrupted database to read, either a copy or the original
+    fn salvage(
+        &mut self,
+        source: &Path,
+        work_dir: &Path,
+        file_state: &FileState,
+        options: &DefragOptions,
+    ) -> Result<()> {
+        self.status = Status::Corrupted;
+        warn!(
+            "Database `{}` is corrupted, salvage it",
+            self.path.display()
+        );
+
+        let Some(backup) = options.backup.as_ref() else {
+            bail!(
+                "Cannot salvage database `{}` without backup",
+                self.path.display()
+            );
+        };
+
+        let tmp_dir = tempdir_in(work_dir)?;
+        if let Some(journal) = options.journal.as_ref() {
+            journal.work_dir(tmp_dir.path())?;
+        }
+        let salvaged_copy = tmp_dir.path().join("salvaged.sqlite");
+        let salvaged =
+            salvage::rebuild(source, &salvaged_copy, self.known.map(|known| known.family))
+                .with_context(|| format!("Failed to salvage database `{}`", self.path.display()))?;
+
+        let connection = sqlite::open(&salvaged_copy)?;
+        if let Some(known) = self.known {
+            functions::register(&connection, known.family)?;
+        }
+        let problems = integrity_check(&connection, false)?;
+        drop(connection);
+        if !problems.is_empty() {
+            bail!(
+                "Salvaged copy of database `{}` failed integrity check, keep the original: {}",
+                self.path.display(),
+                problems.join("; ")
+            );
+        }
+
+        let incomplete: Vec<&str> = salvaged
+            .iter()
+            .filter(|table| !table.is_complete())
+            .map(|table| table.name.as_str())
+            .collect();
+        if !incomplete.is_empty() {
+            bail!(
+                "Salvaged copy of database `{}` lost readable rows of {}, keep the original",
+                self.path.display(),
+                incomplete.join(", ")
+            );
+        }
+
+        if !salvage::confirm(&self.path, &salvaged)? {
+            bail!("Keep corrupted database `{}`", self.path.display());
+        }
+
+        if file_state.is_changed(&self.path)? {
+            self.status = Status::Modified;
+            bail!(
+                "Database `{}` was modified while salvaging, keep the original",
+                self.path.display()
+            );
+        }
+
+        backup.save(&self.path)?;
+        self.replace(options, Some(&salvaged_copy))?;
+
+        self.size_after = Some(self.database_size()?);
+        self.status = Status::Salvaged;
+        Ok(())
     }
 }
 
+/// Check whether any process of a browser is running
+pub fn is_running(browser_name: &str) -> bool {
+    let mut sys = System::new();
+    sys.refresh_processes(ProcessesToUpdate::All, true);
+    sys.processes().values().any(|p| {
+        let name = p.name().to_str().unwrap();
+        name.to_lowercase().contains(&browser_name.to_lowercase())
+    })
+}
+
 impl Defragment for Browser {
-    fn defrag(&mut self, dry_run: bool) -> Result<()> {
-        if !dry_run {
+    fn defrag(&mut self, options: &DefragOptions) -> Result<()> {
+        if !options.dry_run {
             // Check if browser is running?
-            let mut sys = System::new();
-            sys.refresh_processes(ProcessesToUpdate::All, true);
-            if sys.processes().values().any(|p| {
-                let name = p.name().to_str().unwrap();
-                name.to_lowercase().contains(&self.name.to_lowercase())
-            }) {
+            if is_running(&self.name) {
                 bail!("Cannot defrag. `{}` is running!!!", self.name);
             }
         }
@@ -93,8 +362,22 @@ impl Defragment for Browser {
             bail!("Database list is empty");
         }
 
+        self.report = ReportOptions::new(options);
         for dbs in self.database_lists.as_mut().unwrap().iter_mut() {
-            dbs.defrag(dry_run)?;
+            dbs.defrag(options)?;
+        }
+
+        // Keep journal of an interrupted run for `--resume`
+        if let Some(journal) = options.journal.as_ref() {
+            if !is_interrupted() {
+                journal.finish()?;
+            }
+        }
+
+        if let Some(backup) = options.backup.as_ref() {
+            if let Err(err) = backup.prune() {
+                error!("{err:#}");
+            }
         }
 
         Ok(())
@@ -102,23 +385,148 @@ impl Defragment for Browser {
 }
 
 impl Defragment for Profile {
-    fn defrag(&mut self, dry_run: bool) -> Result<()> {
+    fn defrag(&mut self, options: &DefragOptions) -> Result<()> {
         if self.databases.is_none() {
             return Ok(());
         }
 
+        // Databases must be replaced on a filesystem where SQLite's locking is reliable
+        let mut skip_reason: Option<String> = None;
+        match FileSystem::read(&self.path) {
+            Ok(filesystem) => {
+                if filesystem.network && !options.allow_network_fs {
+                    skip_reason = Some(format!("network filesystem {}", filesystem.name));
+                    if !options.dry_run {
+                        warn!("Use `--allow-network-fs` to defrag profiles on network filesystems");
+                    }
+                } else if filesystem.read_only {
+                    skip_reason = Some("read-only filesystem".to_string());
+                } else if filesystem.possibly_network && !options.allow_network_fs {
+                    warn!(
+                        "Profile `{}` is on {}, which may be a network filesystem, e.g. sshfs or rclone, where SQLite's locking is unreliable",
+                        self.path.display(),
+                        filesystem.name
+                    );
+                }
+                self.filesystem = Some(filesystem.name);
+            }
+            Err(err) => warn!("{err:#}"),
+        }
+        if let (Some(reason), false) = (skip_reason, options.dry_run) {
+            warn!("Skip profile `{}` on {reason}", self.path.display());
+            for db in self.databases.as_mut().unwrap().iter_mut() {
+                db.size_before = db.database_size().ok();
+                db.status = Status::Skipped(reason.clone());
+            }
+            return Ok(());
+        }
+
         for db in self.databases.as_mut().unwrap().iter_mut() {
-            if let Err(err) = db.defrag(dry_run) {
-                error!("{err:#}");
+            if is_interrupted() {
+                warn!("Interrupted, skip database `{}`", db.path.display());
+                continue;
+            }
+
+            if let Some(journal) = options.journal.as_ref() {
+                if journal.is_done(&db.path) {
+                    db.status = Status::PreviouslyDone;
+                    continue;
+                }
+                journal.begin(&db.path)?;
+            }
+
+            // A failed or interrupted database is not done, `--resume` retries it
+            match db.defrag(options) {
+                Ok(()) => {
+                    if let Some(journal) = options.journal.as_ref() {
+                        journal.done(&db.path)?;
+                    }
+                }
+                Err(err) => error!("{err:#}"),
+            }
+        }
+
+        if let Some(limits) = options.sessions.as_ref() {
+            if is_interrupted() {
+                warn!(
+                    "Interrupted, skip session store of `{}`",
+                    self.path.display()
+                );
+            } else {
+                self.sessions = Some(session::compact(
+                    &self.path,
+                    limits,
+                    options.dry_run,
+                    options.backup.as_ref(),
+                ));
             }
         }
 
+        // Site data of each browser profile in the directory of its history
+        if let Some(days) = options.purge_stale_site_data {
+            let mut stale: Vec<SiteData> = Vec::new();
+            for db in self.databases.as_ref().unwrap() {
+                if is_interrupted() {
+                    warn!("Interrupted, skip site data of `{}`", self.path.display());
+                    break;
+                }
+
+                let Some(known) = db.known.filter(|known| known.has_visits()) else {
+                    continue;
+                };
+                match site_data::purge_stale(&db.path, known.family, days, options.dry_run) {
+                    Ok(mut data) => stale.append(&mut data),
+                    Err(err) => error!("{err:#}"),
+                }
+            }
+            self.site_data = Some(stale);
+        }
+
         Ok(())
     }
 }
 
 impl Defragment for Database {
-    fn defrag(&mut self, dry_run: bool) -> Result<()> {
+    fn defrag(&mut self, options: &DefragOptions) -> Result<()> {
+        let deadline: Option<Instant> = options.db_timeout.map(|timeout| Instant::now() + timeout);
+        let wal = sidecar_path(&self.path, "-wal");
+        let had_wal = wal.exists();
+        let result = self.vacuum(options, deadline.as_ref());
+
+        // Read-only connection of `VACUUM INTO` creates WAL and shared-memory files of
+        // a WAL database, they are left when the original is not replaced
+        if !options.dry_run && !had_wal && wal.metadata().is_ok_and(|wal| wal.len() == 0) {
+            if let Err(err) = remove_wal(&self.path) {
+                warn!("{err:#}");
+            }
+        }
+
+        // Staging file of `VACUUM INTO` is left when the original is not replaced
+        let staging = staging_path(&self.path);
+        if !options.dry_run && staging.exists() {
+            if let Err(err) = fs::remove_file(&staging) {
+                warn!("Failed to remove `{}`: {err}", staging.display());
+            }
+        }
+        if let (Err(_), Some(deadline)) = (result.as_ref(), deadline) {
+            if Instant::now() >= deadline {
+                self.status = Status::TimedOut;
+                bail!(
+                    "Database `{}` timed out after {} seconds, keep the original",
+                    self.path.display(),
+                    options.db_timeout.unwrap_or_default().as_secs()
+                );
+            }
+        }
+        result
+    }
+}
+
+impl Database {
+    /// Vacuum and reindex a copy of database, then replace the original with it
+    ///
+    /// `deadline` - Interrupt SQLite after this instant, see `--db-timeout`
+    fn vacuum(&mut self, options: &DefragOptions, deadline: Option<&Instant>) -> Result<()> {
         if !self.path.exists() {
             bail!("Database file `{}` is not exist", self.path.display());
         }
@@ -129,33 +537,361 @@ impl Defragment for Database {
             Err(err) => bail!("{err:#}"),
         };
 
-        if dry_run {
+        if options.dry_run {
             self.size_after = self.size_before;
             return Ok(());
         }
 
-        // Copy database file to TMPDIR before defrag
-        let tmp_dir = tempdir()?;
-        let dp_copy = tmp_dir.path().join(self.path.file_name().unwrap());
-        fs::copy(&self.path, &dp_copy)?;
+        let maintained = self.known.filter(|known| {
+            (options.places_maintenance && places::is_places(known))
+                || (options.history_maintenance && history::is_history(known))
+                || (options.purge_expired_cookies && cookies::is_cookies(known))
+                || options.retention.applies_to(known)
+        });
 
-        // Open database file
-        let connection = match sqlite::open(&dp_copy)
-            .with_context(|| format!("Failed to open database `{}`", self.path.display()))
+        // Skip a database with few free pages, which are counted in its header without opening it.
+        // The header may be outdated by commits in the WAL, such a database is always defragged.
+        // A database to maintain is never skipped, its maintenance may free pages.
+        if (options.min_free_ratio.is_some() || options.min_reclaim.is_some())
+            && maintained.is_none()
         {
-            Ok(connection) => connection,
+            let wal_size: u64 = sidecar_path(&self.path, "-wal")
+                .metadata()
+                .map_or(0, |wal| wal.len());
+            if wal_size == 0 {
+                let header = Header::read(&self.path)?;
+                let free_ratio = header.free_ratio(self.path.metadata()?.len());
+                let reclaimable = header.reclaimable();
+                if options
+                    .min_free_ratio
+                    .is_some_and(|ratio| free_ratio < ratio)
+                    || options.min_reclaim.is_some_and(|bytes| reclaimable < bytes)
+                {
+                    info!(
+                        "Skip database `{}`, only {} ({:.1}%) is free",
+                        self.path.display(),
+                        format_size(reclaimable, BINARY),
+                        free_ratio * 100.0
+                    );
+                    self.status = Status::NotFragmented;
+                    return Ok(());
+                }
+            }
+        }
+
+        // Original must be replaceable, e.g. not `chattr +i` or on a read-only bind mount
+        match check_replaceable(&self.path) {
+            Ok(Some(reason)) => {
+                warn!("Skip database `{}`: {reason}", self.path.display());
+                self.status = Status::Skipped(reason.to_string());
+                return Ok(());
+            }
+            Ok(None) => {}
             Err(err) => bail!("{err:#}"),
+        }
+
+        // State of the original before copy, to detect writes by other processes
+        let file_state = FileState::read(&self.path)?;
+
+        // A copy of database and VACUUM's temporary database need up to 3 times its size,
+        // `VACUUM INTO` only writes a compact copy next to the original,
+        // in-place VACUUM writes its temporary database and a rollback journal of the original
+        let data_size: u64 = file_state.size + file_state.wal.map_or(0, |(size, _)| size);
+        let work_dir: PathBuf = options.work_dir.clone().unwrap_or_else(env::temp_dir);
+        let db_dir: PathBuf = self.path.parent().unwrap().to_path_buf();
+        let requirements: Vec<(&Path, u64)> = match options.engine {
+            Engine::Copy => vec![(&work_dir, 3 * data_size)],
+            Engine::VacuumInto => vec![(&db_dir, data_size)],
+            Engine::InPlace if is_same_filesystem(&db_dir, &work_dir) => {
+                vec![(&db_dir, 2 * data_size)]
+            }
+            Engine::InPlace => vec![(&db_dir, data_size), (&work_dir, data_size)],
         };
+        for (space_dir, required_space) in requirements {
+            let free_space: u64 = available_space(space_dir)?;
+            if free_space < required_space {
+                self.status = Status::NoSpace;
+                warn!(
+                    "Skip database `{}`, it needs {} but only {} is available in `{}`",
+                    self.path.display(),
+                    format_size(required_space, BINARY),
+                    format_size(free_space, BINARY),
+                    space_dir.display()
+                );
+                return Ok(());
+            }
+        }
 
-        // VACUUM
-        if let Err(err) = connection
-            .execute("VACUUM;")
-            .with_context(|| format!("Failed to vacuum database `{}`", self.path.display()))
+        // Copy database file to work directory before defrag, other engines open the original
+        let tmp_dir: Option<TempDir> = match options.engine {
+            Engine::Copy => Some(tempdir_in(&work_dir)?),
+            Engine::VacuumInto | Engine::InPlace => None,
+        };
+        let (connection, dp_copy): (Connection, PathBuf) = match tmp_dir.as_ref() {
+            Some(tmp_dir) => {
+                if let Some(journal) = options.journal.as_ref() {
+                    journal.work_dir(tmp_dir.path())?;
+                }
+                let dp_copy = tmp_dir.path().join(self.path.file_name().unwrap());
+                fs::copy(&self.path, &dp_copy)?;
+
+                // Commits which are not checkpointed yet are only in the WAL
+                let wal = sidecar_path(&self.path, "-wal");
+                if wal.exists() {
+                    fs::copy(&wal, sidecar_path(&dp_copy, "-wal"))?;
+                }
+                let flags = OpenFlags::new().with_create().with_read_write();
+                (self.open(&dp_copy, flags, deadline)?, dp_copy)
+            }
+            None if options.engine == Engine::VacuumInto => {
+                let flags = OpenFlags::new().with_read_only();
+                (
+                    self.open(&self.path, flags, deadline)?,
+                    staging_path(&self.path),
+                )
+            }
+            None => {
+                let flags = OpenFlags::new().with_read_write();
+                let connection = self.open(&self.path, flags, deadline)?;
+
+                // Hold an exclusive lock until the connection is closed, so no other process
+                // reads or writes the database while it is vacuumed in place
+                if let Err(err) = connection
+                    .execute("PRAGMA locking_mode = EXCLUSIVE; BEGIN EXCLUSIVE; COMMIT;")
+                    .with_context(|| format!("Failed to lock database `{}`", self.path.display()))
+                {
+                    drop(connection);
+                    bail!("{err:#}");
+                }
+                (connection, self.path.clone())
+            }
+        };
+
+        // A failed check after vacuum in place cannot keep the original
+        let on_failure: &str = match options.engine {
+            Engine::Copy | Engine::VacuumInto => "keep the original",
+            Engine::InPlace if options.backup.is_some() => "restore it from the backup",
+            Engine::InPlace => "it is vacuumed in place without backup",
+        };
+
+        // FTS tables with a tokenizer of the browser cannot be checked
+        match fts::find_unsupported(&connection) {
+            Ok(Some(requirement)) => {
+                drop(connection);
+                warn!(
+                    "Skip database `{}`, it requires {requirement}",
+                    self.path.display()
+                );
+                self.status = Status::Skipped(format!("requires {requirement}"));
+                return Ok(());
+            }
+            Ok(None) => {}
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        }
+
+        // Keep VACUUM's temporary database on disk in work directory, see `SQLITE_TMPDIR`
+        if options.work_dir.is_some() {
+            if let Err(err) = connection.execute("PRAGMA temp_store = FILE;") {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        }
+
+        // Salvage rebuilds from the copy, or from the original opened read-only
+        let salvage_source: PathBuf = match options.engine {
+            Engine::Copy => dp_copy.clone(),
+            Engine::VacuumInto | Engine::InPlace => self.path.clone(),
+        };
+
+        // Check the copy before defrag, do not bake an existing corruption into a new file
+        let problems = match integrity_check(&connection, options.quick_check) {
+            Ok(problems) => problems,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+        if !problems.is_empty() {
+            drop(connection);
+            if options.salvage {
+                return self.salvage(&salvage_source, &work_dir, &file_state, options);
+            }
+            self.status = Status::Corrupted;
+            bail!(
+                "Database `{}` is corrupted: {}",
+                self.path.display(),
+                problems.join("; ")
+            );
+        }
+
+        // Keep the original in a backup before it is changed in place
+        if options.engine == Engine::InPlace {
+            if let Some(backup) = options.backup.as_ref() {
+                if let Err(err) = backup.save(&self.path) {
+                    drop(connection);
+                    bail!("{err:#}");
+                }
+            }
+        }
+
+        // Merge segments of FTS indexes before the signature, it changes their shadow tables.
+        // The original of `VACUUM INTO` is opened read-only, so its indexes cannot be merged.
+        let fts_size_before: Option<u64> = self.fts_size(&connection);
+        let mut fts_merged = false;
+        if let Some(mode) = options.fts {
+            if options.engine == Engine::VacuumInto {
+                if fts_size_before.is_some() {
+                    warn!(
+                        "Cannot merge FTS indexes of database `{}` with `VACUUM INTO`",
+                        self.path.display()
+                    );
+                }
+            } else {
+                match fts::maintain(&connection, mode) {
+                    Ok(changed) => fts_merged = changed,
+                    Err(err) => {
+                        drop(connection);
+                        bail!("{err:#}");
+                    }
+                }
+            }
+        }
+
+        // Prune history, remove orphans and expired rows before the signature, it changes their tables
+        if let Some(known) = maintained {
+            if options.engine == Engine::VacuumInto {
+                warn!(
+                    "Cannot run maintenance of database `{}` with `VACUUM INTO`",
+                    self.path.display()
+                );
+            } else {
+                match self.maintain(&connection, known, options) {
+                    Ok(cleanups) => self.cleanups = Some(cleanups),
+                    Err(err) => {
+                        drop(connection);
+                        bail!("{err:#}");
+                    }
+                }
+            }
+        }
+
+        // Signature of database before vacuum
+        let signature_before = match Signature::read(&connection, options.verify)
+            .with_context(|| format!("Failed to verify database `{}`", self.path.display()))
         {
+            Ok(signature) => signature,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+
+        let auto_vacuum_before = match read_auto_vacuum(&connection) {
+            Ok(mode) => mode,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+        let page_size_before = match read_page_size(&connection) {
+            Ok(page_size) => page_size,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+        let journal_mode = match read_journal_mode(&connection) {
+            Ok(journal_mode) => journal_mode,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+
+        // Change auto-vacuum mode before VACUUM, which rebuilds the database in the new mode.
+        // Switching between full and incremental only rewrites the header, which the read-only
+        // original of `VACUUM INTO` does not allow, so it is done on the compact copy instead.
+        let auto_vacuum_deferred = options.auto_vacuum.filter(|mode| {
+            options.engine == Engine::VacuumInto
+                && auto_vacuum_before != AutoVacuum::None
+                && *mode != AutoVacuum::None
+        });
+        if let Some(mode) = options
+            .auto_vacuum
+            .filter(|_| auto_vacuum_deferred.is_none())
+        {
+            if let Err(err) = connection.execute(format!("PRAGMA auto_vacuum = {};", mode.pragma()))
+            {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        }
+
+        // Change page size before VACUUM, which is the only time it can be changed.
+        // VACUUM keeps the page size of a WAL database, so it leaves WAL mode until VACUUM is done,
+        // `VACUUM INTO` applies it to the compact copy in any journal mode.
+        let page_size: Option<u32> = match options.page_size {
+            Some(PageSize::Auto) => self.known.map(|known| known.page_size),
+            Some(PageSize::Bytes(page_size)) => Some(page_size),
+            None => None,
+        }
+        .filter(|page_size| *page_size != page_size_before);
+        let leave_wal: bool =
+            page_size.is_some() && journal_mode == "wal" && options.engine != Engine::VacuumInto;
+        if leave_wal {
+            if let Err(err) = connection.execute("PRAGMA journal_mode = DELETE;") {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        }
+        if let Some(page_size) = page_size {
+            if let Err(err) = connection.execute(format!("PRAGMA page_size = {page_size};")) {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        }
+
+        // VACUUM
+        let vacuumed = match options.engine {
+            Engine::Copy | Engine::InPlace => connection.execute("VACUUM;"),
+            Engine::VacuumInto => vacuum_into(&connection, &dp_copy),
+        };
+        if let Err(err) = vacuumed {
             drop(connection);
-            bail!("{err:#}");
+            if options.salvage && is_corrupt(&err) {
+                return self.salvage(&salvage_source, &work_dir, &file_state, options);
+            }
+            bail!("Failed to vacuum database `{}`: {err}", self.path.display());
         }
 
+        // Continue with the compact copy written by `VACUUM INTO`, in the journal mode of the original
+        let connection = match options.engine {
+            Engine::Copy | Engine::InPlace => {
+                if leave_wal {
+                    if let Err(err) = connection.execute("PRAGMA journal_mode = WAL;") {
+                        drop(connection);
+                        bail!("{err:#}");
+                    }
+                }
+                connection
+            }
+            Engine::VacuumInto => {
+                drop(connection);
+                let connection =
+                    self.open(&dp_copy, OpenFlags::new().with_read_write(), deadline)?;
+                if journal_mode == "wal" {
+                    connection.execute("PRAGMA journal_mode = WAL;")?;
+                }
+                if let Some(mode) = auto_vacuum_deferred {
+                    connection.execute(format!("PRAGMA auto_vacuum = {};", mode.pragma()))?;
+                }
+                connection
+            }
+        };
+
         // REINDEX
         if let Err(err) = connection
             .execute("REINDEX;")
@@ -165,20 +901,375 @@ impl Defragment for Database {
             bail!("{err:#}");
         }
 
-        // Copy database file from TMPDIR to original location if file size smaller than original
-        if dp_copy.metadata()?.len() < self.size_before.unwrap() {
-            fs::copy(&dp_copy, &self.path)?;
+        // Check the vacuumed copy before replacing the original
+        let problems = match integrity_check(&connection, options.quick_check) {
+            Ok(problems) => problems,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+        if !problems.is_empty() {
+            drop(connection);
+            self.status = Status::CheckFailed;
+            bail!(
+                "Vacuumed database `{}` failed integrity check, {on_failure}: {}",
+                self.path.display(),
+                problems.join("; ")
+            );
+        }
+
+        // Compare content of vacuumed copy with the original
+        let signature_after = match Signature::read(&connection, options.verify)
+            .with_context(|| format!("Failed to verify database `{}`", self.path.display()))
+        {
+            Ok(signature) => signature,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+        let mismatches = signature_before.compare(&signature_after);
+        if !mismatches.is_empty() {
+            drop(connection);
+            self.status = Status::VerifyFailed;
+            bail!(
+                "Vacuumed database `{}` differs from the original, {on_failure}: {}",
+                self.path.display(),
+                mismatches.join("; ")
+            );
+        }
+
+        let auto_vacuum_after = match read_auto_vacuum(&connection) {
+            Ok(mode) => mode,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+        self.auto_vacuum = Some((auto_vacuum_before, auto_vacuum_after));
+        let page_size_after = match read_page_size(&connection) {
+            Ok(page_size) => page_size,
+            Err(err) => {
+                drop(connection);
+                bail!("{err:#}");
+            }
+        };
+        self.page_size = Some((page_size_before, page_size_after));
+        let fts_size_after: Option<u64> = self.fts_size(&connection);
+        self.fts_size = fts_size_before.zip(fts_size_after);
+
+        // Refresh statistics of query planner after verifying, they change `sqlite_stat*` tables
+        if let Some(statistics) = options.statistics {
+            match refresh_statistics(&connection, statistics, options.analysis_limit) {
+                Ok(()) => self.statistics_refreshed = true,
+                Err(err) => warn!(
+                    "Failed to refresh statistics of database `{}`: {err}",
+                    self.path.display()
+                ),
+            }
+        }
+
+        // Copy database file from work directory to original location if file size smaller than original
+        // or its statistics or format are changed, the copy includes content of the original's WAL
+        let original_size: u64 = self.size_before.unwrap();
+
+        // Move vacuumed pages from WAL into the database file, only the file is copied back
+        if options.engine != Engine::VacuumInto {
+            if let Err(err) = connection.execute("PRAGMA wal_checkpoint(TRUNCATE);") {
+                drop(connection);
+                bail!("{err:#}");
+            }
         }
 
+        let replaced: bool = if options.engine == Engine::InPlace {
+            // The lock is released on close
+            drop(connection);
+            true
+        } else if dp_copy.metadata()?.len() < original_size
+            || self.statistics_refreshed
+            || auto_vacuum_after != auto_vacuum_before
+            || page_size_after != page_size_before
+            || fts_merged
+            || self
+                .cleanups
+                .as_ref()
+                .is_some_and(|cleanups| !cleanups.is_empty())
+        {
+            if file_state.is_changed(&self.path)? {
+                drop(connection);
+                self.status = Status::Modified;
+                bail!(
+                    "Database `{}` was modified while defragging, keep the original",
+                    self.path.display()
+                );
+            }
+            if let Some(backup) = options.backup.as_ref() {
+                if let Err(err) = backup.save(&self.path) {
+                    drop(connection);
+                    bail!("{err:#}");
+                }
+            }
+            drop(connection);
+            let replaced = match options.engine {
+                Engine::VacuumInto => self.replace(options, None),
+                Engine::Copy | Engine::InPlace => self.replace(options, Some(&dp_copy)),
+            };
+            if let Err(err) = replaced {
+                bail!("{err:#}");
+            }
+            true
+        } else {
+            drop(connection);
+            self.statistics_refreshed = false;
+            self.fts_size = fts_size_before.map(|size| (size, size));
+            false
+        };
+
         // Size of database after defrag
         match self.database_size() {
             Ok(size_after) => self.size_after = Some(size_after),
             Err(err) => bail!("{err:#}"),
         };
 
-        self.defrag = true;
-        drop(connection);
+        // Vacuum did not make the original any smaller, it is kept
+        self.status = if replaced {
+            Status::Defragged
+        } else {
+            Status::NotFragmented
+        };
 
         Ok(())
     }
+
+    /// Size of FTS shadow tables, `None` without FTS tables or if it cannot be counted
+    fn fts_size(&self, connection: &Connection) -> Option<u64> {
+        match fts::shadow_size(connection) {
+            Ok(size) => size,
+            Err(err) => {
+                warn!(
+                    "Size of FTS tables of `{}` is unavailable: {err:#}",
+                    self.path.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Replace database with its staging file, which is written from `source` if given
+    ///
+    /// The journal records the replacement once the staging file is complete, so the next run
+    /// removes the WAL of the original if the run crashes right after the rename.
+    fn replace(&self, options: &DefragOptions, source: Option<&Path>) -> Result<()> {
+        if let Some(source) = source {
+            write_staging(source, &self.path)?;
+        }
+        if let Some(journal) = options.journal.as_ref() {
+            journal.replace(&self.path)?;
+        }
+
+        install_staging(&self.path)
+    }
+
+    /// Run maintenance of a known database, databases of each maintenance do not overlap
+    fn maintain(
+        &self,
+        connection: &Connection,
+        known: &KnownDatabase,
+        options: &DefragOptions,
+    ) -> Result<Vec<Cleanup>> {
+        if places::is_places(known) {
+            places::maintain(
+                connection,
+                known,
+                &options.retention,
+                options.places_maintenance,
+            )
+        } else if history::is_history(known) {
+            history::maintain(
+                connection,
+                known,
+                &self.path.with_file_name("History"),
+                &options.retention,
+            )
+        } else if cookies::is_cookies(known) {
+            cookies::purge_expired(connection, known)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Open a database with the busy timeout, deadline and functions of its browser
+    ///
+    /// `deadline` - Interrupt SQLite after this instant, it must outlive the connection
+    fn open(
+        &self,
+        path: &Path,
+        flags: OpenFlags,
+        deadline: Option<&Instant>,
+    ) -> Result<Connection> {
+        // Maintenance attaches other databases read-only by their URIs
+        let mut connection = Connection::open_with_flags(path, flags.with_uri())
+            .with_context(|| format!("Failed to open database `{}`", self.path.display()))?;
+
+        // Wait for a lock instead of failing immediately
+        connection.set_busy_timeout(BUSY_TIMEOUT.as_millis() as usize)?;
+
+        // Interrupt a long running statement of a huge database
+        if let Some(deadline) = deadline {
+            unsafe { set_deadline(&connection, deadline) };
+        }
+
+        // Schema of a browser database may refer to functions which only exist in that browser
+        if let Some(known) = self.known {
+            functions::register(&connection, known.family)?;
+        }
+
+        Ok(connection)
+    }
+}
+
+/// Write a compact copy of database to `target` with `VACUUM INTO`
+fn vacuum_into(connection: &Connection, target: &Path) -> sqlite::Result<()> {
+    // `VACUUM INTO` does not overwrite an existing file, e.g. a leftover of a crashed run
+    let _ = fs::remove_file(target);
+
+    let mut statement = connection.prepare("VACUUM INTO ?;")?;
+    statement.bind((1, target.to_string_lossy().as_ref()))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Refresh `sqlite_stat1` and `sqlite_stat4` used by query planner
+fn refresh_statistics(
+    connection: &Connection,
+    statistics: Statistics,
+    analysis_limit: Option<u32>,
+) -> sqlite::Result<()> {
+    if let Some(limit) = analysis_limit {
+        connection.execute(format!("PRAGMA analysis_limit = {limit};"))?;
+    }
+
+    match statistics {
+        Statistics::Analyze => connection.execute("ANALYZE;"),
+        // Analyze every table which may benefit from it, not only tables queried by this connection
+        // See: https://www.sqlite.org/pragma.html#pragma_optimize
+        Statistics::Optimize => connection.execute("PRAGMA optimize = 0x10002;"),
+    }
+}
+
+fn read_auto_vacuum(connection: &Connection) -> Result<AutoVacuum> {
+    let mut statement = connection.prepare("PRAGMA auto_vacuum;")?;
+    statement.next()?;
+    match statement.read::<i64, _>(0)? {
+        0 => Ok(AutoVacuum::None),
+        1 => Ok(AutoVacuum::Full),
+        2 => Ok(AutoVacuum::Incremental),
+        mode => bail!("Unknown auto-vacuum mode {mode}"),
+    }
+}
+
+fn read_page_size(connection: &Connection) -> sqlite::Result<u32> {
+    let mut statement = connection.prepare("PRAGMA page_size;")?;
+    statement.next()?;
+    Ok(statement.read::<i64, _>(0)? as u32)
+}
+
+fn read_journal_mode(connection: &Connection) -> sqlite::Result<String> {
+    let mut statement = connection.prepare("PRAGMA journal_mode;")?;
+    statement.next()?;
+    statement.read::<String, _>(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_database_size_counts_wal() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("test.sqlite");
+
+        // Commits stay in the WAL while the connection is open
+        let connection = sqlite::open(&path).unwrap();
+        connection
+            .execute(
+                "PRAGMA journal_mode = WAL; PRAGMA wal_autocheckpoint = 0;
+                 CREATE TABLE t (data BLOB); INSERT INTO t VALUES (zeroblob(65536));",
+            )
+            .unwrap();
+
+        let wal_size = sidecar_path(&path, "-wal").metadata().unwrap().len();
+        assert!(wal_size > 0);
+        assert_eq!(
+            Database::new(&path).database_size().unwrap(),
+            path.metadata().unwrap().len() + wal_size
+        );
+    }
+
+    #[test]
+    fn test_copy_wal_database() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("test.sqlite");
+
+        let connection = sqlite::open(&path).unwrap();
+        connection
+            .execute(
+                "PRAGMA journal_mode = WAL;
+                 CREATE TABLE t (id INTEGER PRIMARY KEY, data BLOB);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+                 INSERT INTO t SELECT i, zeroblob(4096) FROM n;
+                 DELETE FROM t WHERE id > 10;",
+            )
+            .unwrap();
+        // Last connection checkpoints and removes WAL, vacuum of the copy writes to its own WAL
+        drop(connection);
+        let original_size = path.metadata().unwrap().len();
+
+        let mut db = Database::new(&path);
+        db.defrag(&DefragOptions::default()).unwrap();
+        assert_eq!(db.status, Status::Defragged);
+        assert!(path.metadata().unwrap().len() < original_size);
+
+        let replaced = sqlite::open(&path).unwrap();
+        let mut statement = replaced.prepare("SELECT count(*) FROM t").unwrap();
+        statement.next().unwrap();
+        assert_eq!(statement.read::<i64, _>(0).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_vacuum_into_wal_database() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("test.sqlite");
+        let connection = sqlite::open(&path).unwrap();
+        connection
+            .execute(
+                "PRAGMA journal_mode = WAL;
+                 CREATE TABLE t (id INTEGER PRIMARY KEY, data BLOB);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+                 INSERT INTO t SELECT i, zeroblob(4096) FROM n;
+                 DELETE FROM t WHERE id > 10;",
+            )
+            .unwrap();
+        // Last connection checkpoints and removes WAL
+        drop(connection);
+        assert!(!sidecar_path(&path, "-wal").exists());
+
+        let options = DefragOptions {
+            engine: Engine::VacuumInto,
+            ..Default::default()
+        };
+        let mut db = Database::new(&path);
+        db.defrag(&options).unwrap();
+        assert_eq!(db.status, Status::Defragged);
+        assert!(db.size_after < db.size_before);
+        for leftover in [
+            sidecar_path(&path, "-wal"),
+            sidecar_path(&path, "-shm"),
+            staging_path(&path),
+        ] {
+            assert!(!leftover.exists(), "{} is left", leftover.display());
+        }
+    }
 }
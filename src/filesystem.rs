@@ -0,0 +1,138 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    mem::MaybeUninit,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+/// `_IOR('f', 1, long)`, see `linux/fs.h`
+const FS_IOC_GETFLAGS: libc::c_ulong = 0x80086601;
+const FS_IMMUTABLE_FL: libc::c_int = 0x00000010;
+const FS_APPEND_FL: libc::c_int = 0x00000020;
+
+/// `FUSE_SUPER_MAGIC`, local like ntfs-3g or remote like sshfs and rclone
+const FUSE_SUPER_MAGIC: u64 = 0x65735546;
+
+/// Magic numbers of `statfs`, see `linux/magic.h`
+const FILESYSTEMS: &[(u64, &str, bool)] = &[
+    (0xEF53, "ext2/3/4", false),
+    (0x9123683E, "btrfs", false),
+    (0x58465342, "xfs", false),
+    (0x2FC12FC1, "zfs", false),
+    (0xCA451A4E, "bcachefs", false),
+    (0xF2F52010, "f2fs", false),
+    (0x01021994, "tmpfs", false),
+    (0x794C7630, "overlayfs", false),
+    (FUSE_SUPER_MAGIC, "fuse", false),
+    (0x0000F15F, "ecryptfs", false),
+    (0x00004D44, "vfat", false),
+    (0x2011BAB0, "exfat", false),
+    (0x5346544E, "ntfs", false),
+    (0x00006969, "nfs", true),
+    (0x0000517B, "smb", true),
+    (0xFF534D42, "cifs", true),
+    (0xFE534D42, "smb2", true),
+    (0x5346414F, "afs", true),
+    (0x00C36400, "ceph", true),
+    (0x01021997, "9p", true),
+    (0x73757245, "coda", true),
+    (0x0000564C, "ncp", true),
+    (0x0BD00BD0, "lustre", true),
+    (0x01161970, "gfs2", true),
+    (0x7461636F, "ocfs2", true),
+];
+
+/// Filesystem of a path
+#[derive(Debug, PartialEq)]
+pub struct FileSystem {
+    /// Type of filesystem, e.g. `btrfs`, or its magic number if unknown
+    pub name: String,
+
+    /// Mounted read-only, including read-only bind mounts
+    pub read_only: bool,
+
+    /// SQLite's locking is unreliable on network filesystems
+    pub network: bool,
+
+    /// Filesystem may be on network, which cannot be told by its type, i.e. FUSE
+    pub possibly_network: bool,
+}
+
+impl FileSystem {
+    pub fn read(path: &Path) -> Result<Self> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+        let mut stat = MaybeUninit::<libc::statfs>::uninit();
+        if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            bail!(
+                "Failed to get filesystem of `{}`: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            );
+        }
+        let magic = unsafe { stat.assume_init() }.f_type as u64 & 0xFFFFFFFF;
+
+        let mut vfs_stat = MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(c_path.as_ptr(), vfs_stat.as_mut_ptr()) } != 0 {
+            bail!(
+                "Failed to get filesystem of `{}`: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            );
+        }
+        let read_only = unsafe { vfs_stat.assume_init() }.f_flag & libc::ST_RDONLY != 0;
+
+        let (name, network) = match FILESYSTEMS.iter().find(|(m, _, _)| *m == magic) {
+            Some((_, name, network)) => (name.to_string(), *network),
+            None => (format!("{magic:#x}"), false),
+        };
+
+        Ok(Self {
+            name,
+            read_only,
+            network,
+            possibly_network: magic == FUSE_SUPER_MAGIC,
+        })
+    }
+}
+
+/// Check whether a database can be replaced, return the reason if it cannot
+///
+/// Replacing creates a staging file next to the database and renames it over the database,
+/// so both the database and its directory must be writable.
+pub fn check_replaceable(path: &Path) -> Result<Option<&'static str>> {
+    if FileSystem::read(path)?.read_only {
+        return Ok(Some("read-only filesystem"));
+    }
+
+    let flags = inode_flags(path)?;
+    if flags & FS_IMMUTABLE_FL != 0 {
+        return Ok(Some("immutable file"));
+    }
+    if flags & FS_APPEND_FL != 0 {
+        return Ok(Some("append-only file"));
+    }
+
+    if let Some(dir) = path.parent() {
+        if inode_flags(dir)? & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+            return Ok(Some("immutable directory"));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Inode flags of `chattr`, 0 if the filesystem does not support them
+fn inode_flags(path: &Path) -> Result<libc::c_int> {
+    let file = File::open(path).with_context(|| format!("Failed to open `{}`", path.display()))?;
+
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } != 0 {
+        return Ok(0);
+    }
+
+    Ok(flags)
+}
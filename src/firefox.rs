@@ -93,7 +93,10 @@ pub fn load_profiles(profiles_ini: &Path) -> Result<Vec<Profile>> {
         profiles.push(Profile {
             name,
             path: profile_path,
+            filesystem: None,
             databases: None,
+            sessions: None,
+            site_data: None,
         });
 
         profile_index += 1;
@@ -126,7 +129,10 @@ mod tests {
                 "tests/",
                 "qioxtndq.default",
             )),
+            filesystem: None,
             databases: None,
+            sessions: None,
+            site_data: None,
         }];
 
         assert_eq!(profiles, expected_profiles);
@@ -151,17 +157,26 @@ mod tests {
                     "tests/",
                     "qioxtndq.default"
                 )),
+                filesystem: None,
                 databases: None,
+                sessions: None,
+                site_data: None,
             },
             Profile {
                 name: "alicew".to_string(),
                 path: PathBuf::from("/home/user/.mozilla/firefox/alicew"),
+                filesystem: None,
                 databases: None,
+                sessions: None,
+                site_data: None,
             },
             Profile {
                 name: "sheldon".to_string(),
                 path: PathBuf::from("/home/user/.mozilla/firefox/sheldon"),
+                filesystem: None,
                 databases: None,
+                sessions: None,
+                site_data: None,
             },
         ];
 
@@ -0,0 +1,309 @@
+use anyhow::{Context, Result};
+use sqlite::{Connection, State};
+use tracing::debug;
+
+use crate::{args::FtsMode, common::quote_identifier};
+
+/// Name of temporary table used to probe FTS modules and tokenizers
+const PROBE_TABLE: &str = "temp.browser_defrag_fts_probe";
+
+/// Suffixes of shadow tables which keep content and index of FTS3/4 and FTS5 tables
+const SHADOW_SUFFIXES: [&str; 8] = [
+    "content", "segments", "segdir", "docsize", "stat", "data", "idx", "config",
+];
+
+/// Number of pages merged by each `merge` command
+const MERGE_PAGES: u32 = 500;
+
+/// Full-text search virtual table
+#[derive(Debug, PartialEq)]
+pub struct FtsTable {
+    pub name: String,
+
+    /// `fts3`, `fts4` or `fts5`
+    pub module: String,
+
+    /// Value of `tokenize` argument, e.g. `porter` or `'unicode61 remove_diacritics 1'`
+    pub tokenize: Option<String>,
+}
+
+impl FtsTable {
+    /// Parse `CREATE VIRTUAL TABLE ... USING fts*(...)`, `None` for other tables
+    pub fn parse(name: &str, sql: &str) -> Option<Self> {
+        let upper = sql.to_ascii_uppercase();
+        if !upper.starts_with("CREATE VIRTUAL TABLE") {
+            return None;
+        }
+
+        let using = upper.find(" USING ")? + " USING ".len();
+        let rest = sql[using..].trim_start();
+        let module_end = rest
+            .find(|c: char| c == '(' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let module = rest[..module_end].to_ascii_lowercase();
+        if !matches!(module.as_str(), "fts3" | "fts4" | "fts5") {
+            return None;
+        }
+
+        let arguments = match (rest.find('('), rest.rfind(')')) {
+            (Some(open), Some(close)) if open < close => &rest[open + 1..close],
+            _ => "",
+        };
+        let tokenize = split_arguments(arguments)
+            .into_iter()
+            .find_map(|argument| tokenize_value(argument).map(|value| value.to_string()));
+
+        Some(Self {
+            name: name.to_string(),
+            module,
+            tokenize,
+        })
+    }
+
+    /// Name of tokenizer, the first word of `tokenize` argument
+    pub fn tokenizer(&self) -> Option<String> {
+        let value = unquote(self.tokenize.as_deref()?);
+        value
+            .split_whitespace()
+            .next()
+            .map(|name| unquote(name).to_string())
+    }
+}
+
+/// Read FTS tables of a database
+pub fn fts_tables(connection: &Connection) -> Result<Vec<FtsTable>> {
+    let mut statement = connection
+        .prepare("SELECT name, sql FROM sqlite_schema WHERE type = 'table' AND rootpage = 0;")
+        .context("Failed to read schema")?;
+
+    let mut tables: Vec<FtsTable> = Vec::new();
+    while let State::Row = statement.next()? {
+        let name = statement.read::<String, _>(0)?;
+        let sql = statement.read::<Option<String>, _>(1)?.unwrap_or_default();
+        if let Some(table) = FtsTable::parse(&name, &sql) {
+            tables.push(table);
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Find the first FTS module or tokenizer which is not available to this connection
+///
+/// Return what is missing, e.g. `tokenizer mozporter` or `module fts5`.
+/// Such tables cannot be checked, so the database must not be defragged.
+pub fn find_unsupported(connection: &Connection) -> Result<Option<String>> {
+    for table in fts_tables(connection)? {
+        if let Err(err) = probe(connection, &table.module, None) {
+            debug!("FTS table `{}` is not supported: {err}", table.name);
+            return Ok(Some(format!("module {}", table.module)));
+        }
+
+        if let Some(tokenize) = table.tokenize.as_deref() {
+            if let Err(err) = probe(connection, &table.module, Some(tokenize)) {
+                debug!("FTS table `{}` is not supported: {err}", table.name);
+                let tokenizer = table.tokenizer().unwrap_or_else(|| tokenize.to_string());
+                return Ok(Some(format!("tokenizer {tokenizer}")));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Merge segments of every FTS index, VACUUM does not merge them
+///
+/// `optimize` merges all segments of an index into one,
+/// `merge` only merges segments of the same level until there is nothing left to merge.
+/// Return whether any index is changed.
+pub fn maintain(connection: &Connection, mode: FtsMode) -> Result<bool> {
+    let mut changed = false;
+    for table in fts_tables(connection)?.iter() {
+        let name = quote_identifier(&table.name);
+        let result = match mode {
+            FtsMode::Optimize => run_command(
+                connection,
+                &format!("INSERT INTO {name}({name}) VALUES('optimize');"),
+            ),
+            FtsMode::Merge => merge(connection, table),
+        };
+        changed |= result
+            .with_context(|| format!("Failed to merge segments of FTS table `{}`", table.name))?;
+    }
+
+    Ok(changed)
+}
+
+/// Run `merge` command until it does no work, return whether it did any
+fn merge(connection: &Connection, table: &FtsTable) -> sqlite::Result<bool> {
+    let name = quote_identifier(&table.name);
+    let command = match table.module.as_str() {
+        "fts5" => format!("INSERT INTO {name}({name}, rank) VALUES('merge', {MERGE_PAGES});"),
+        _ => format!("INSERT INTO {name}({name}) VALUES('merge={MERGE_PAGES},8');"),
+    };
+
+    let mut changed = false;
+    while run_command(connection, &command)? {
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Run a command of an FTS table, return whether it did any work
+///
+/// A command which does no work changes less than 2 rows.
+fn run_command(connection: &Connection, command: &str) -> sqlite::Result<bool> {
+    let before = connection.total_change_count();
+    connection.execute(command)?;
+    Ok(connection.total_change_count() - before >= 2)
+}
+
+/// Size in bytes of FTS shadow tables and their indexes, `None` without FTS tables
+///
+/// Pages are counted by the `dbstat` virtual table.
+pub fn shadow_size(connection: &Connection) -> Result<Option<u64>> {
+    let tables = fts_tables(connection)?;
+    if tables.is_empty() {
+        return Ok(None);
+    }
+
+    let mut statement = connection
+        .prepare(
+            "SELECT sum(pgsize) FROM dbstat
+             WHERE name IN (SELECT name FROM sqlite_schema WHERE tbl_name = ?);",
+        )
+        .context("Failed to read size of FTS tables")?;
+
+    let mut size: u64 = 0;
+    for table in tables.iter() {
+        for suffix in SHADOW_SUFFIXES {
+            statement.reset()?;
+            statement.bind((1, format!("{}_{suffix}", table.name).as_str()))?;
+            statement.next()?;
+            size += statement.read::<Option<i64>, _>(0)?.unwrap_or_default() as u64;
+        }
+    }
+
+    Ok(Some(size))
+}
+
+/// Create and drop a temporary FTS table with the same module and tokenizer
+fn probe(connection: &Connection, module: &str, tokenize: Option<&str>) -> sqlite::Result<()> {
+    let arguments = match tokenize {
+        Some(tokenize) => format!("content, tokenize={tokenize}"),
+        None => "content".to_string(),
+    };
+    connection.execute(format!(
+        "CREATE VIRTUAL TABLE {PROBE_TABLE} USING {module}({arguments});"
+    ))?;
+    connection.execute(format!("DROP TABLE {PROBE_TABLE};"))
+}
+
+/// Split module arguments at commas outside of quotes and parentheses
+fn split_arguments(arguments: &str) -> Vec<&str> {
+    let mut parts: Vec<&str> = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in arguments.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if (q == c) || (q == '[' && c == ']') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`' | '[') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(arguments[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(arguments[start..].trim());
+    parts
+}
+
+/// Value of `tokenize=<VALUE>` or FTS3's `tokenize <VALUE>` argument
+fn tokenize_value(argument: &str) -> Option<&str> {
+    let key = argument.get(.."tokenize".len())?;
+    if !key.eq_ignore_ascii_case("tokenize") {
+        return None;
+    }
+
+    let rest = &argument["tokenize".len()..];
+    let value = match rest.trim_start().strip_prefix('=') {
+        Some(value) => value,
+        None if rest.starts_with(char::is_whitespace) => rest,
+        None => return None,
+    };
+    Some(value.trim()).filter(|value| !value.is_empty())
+}
+
+/// Remove quotes of an SQL string or identifier
+fn unquote(value: &str) -> &str {
+    for (open, close) in [('\'', '\''), ('"', '"'), ('`', '`'), ('[', ']')] {
+        if let Some(inner) = value
+            .strip_prefix(open)
+            .and_then(|value| value.strip_suffix(close))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_maintain_changes() {
+        let connection = sqlite::open(":memory:").unwrap();
+        connection
+            .execute("CREATE VIRTUAL TABLE t USING fts5(body);")
+            .unwrap();
+        // Each commit writes a segment
+        for i in 0..20 {
+            connection
+                .execute(format!("INSERT INTO t VALUES ('segment {i}');"))
+                .unwrap();
+        }
+
+        assert!(maintain(&connection, FtsMode::Optimize).unwrap());
+        assert!(!maintain(&connection, FtsMode::Optimize).unwrap());
+        assert!(!maintain(&connection, FtsMode::Merge).unwrap());
+    }
+
+    #[test]
+    fn test_parse_fts_table() {
+        let table = FtsTable::parse(
+            "messagesText",
+            "CREATE VIRTUAL TABLE messagesText USING fts3(tokenize mozporter, body, subject)",
+        )
+        .unwrap();
+        assert_eq!(table.module, "fts3");
+        assert_eq!(table.tokenizer(), Some("mozporter".to_string()));
+
+        let table = FtsTable::parse(
+            "search",
+            "CREATE VIRTUAL TABLE search USING FTS5(title, body, tokenize = 'porter unicode61 remove_diacritics 1')",
+        )
+        .unwrap();
+        assert_eq!(table.module, "fts5");
+        assert_eq!(
+            table.tokenize.as_deref(),
+            Some("'porter unicode61 remove_diacritics 1'")
+        );
+        assert_eq!(table.tokenizer(), Some("porter".to_string()));
+
+        let table = FtsTable::parse("t", "CREATE VIRTUAL TABLE t USING fts4(a, b)").unwrap();
+        assert_eq!(table.tokenize, None);
+
+        assert_eq!(
+            FtsTable::parse("r", "CREATE VIRTUAL TABLE r USING rtree(id, x0, x1)"),
+            None
+        );
+        assert_eq!(FtsTable::parse("t", "CREATE TABLE t(tokenize)"), None);
+    }
+}
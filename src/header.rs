@@ -0,0 +1,149 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+/// Size of SQLite database header
+pub const HEADER_SIZE: usize = 100;
+
+/// Header string at the start of every SQLite database file
+pub const MAGIC: &[u8; 16] = b"SQLite format 3\x00";
+
+/// Fields of SQLite database header
+///
+/// See: https://www.sqlite.org/fileformat.html#the_database_header
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    /// Page size in bytes at offset 16
+    pub page_size: u32,
+
+    /// File change counter at offset 24
+    pub change_counter: u32,
+
+    /// Size of database in pages at offset 28
+    pub page_count: u32,
+
+    /// Total number of freelist pages at offset 36
+    pub freelist_count: u32,
+
+    /// Change counter when `page_count` was written at offset 92
+    pub version_valid_for: u32,
+}
+
+impl Header {
+    /// Read header of a database file
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::parse(&read_bytes(path)?)
+            .with_context(|| format!("Invalid header of `{}`", path.display()))
+    }
+
+    /// Parse header from the first 100 bytes of a database file
+    pub fn parse(header: &[u8]) -> Result<Self> {
+        if header.len() < HEADER_SIZE {
+            bail!("Header is too short: {} bytes", header.len());
+        }
+
+        if !header.starts_with(MAGIC) {
+            bail!("Header string does not match");
+        }
+
+        // Page size of 65536 is stored as 1
+        let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+            1 => 65536,
+            page_size => page_size as u32,
+        };
+
+        Ok(Self {
+            page_size,
+            change_counter: read_u32(header, 24),
+            page_count: read_u32(header, 28),
+            freelist_count: read_u32(header, 36),
+            version_valid_for: read_u32(header, 92),
+        })
+    }
+
+    /// Number of pages, computed from the file size if the header's is written by an old SQLite
+    pub fn page_count(&self, file_size: u64) -> u64 {
+        if self.page_count != 0 && self.change_counter == self.version_valid_for {
+            self.page_count as u64
+        } else {
+            file_size / self.page_size.max(1) as u64
+        }
+    }
+
+    /// Ratio of free pages to all pages
+    pub fn free_ratio(&self, file_size: u64) -> f64 {
+        match self.page_count(file_size) {
+            0 => 0.0,
+            page_count => self.freelist_count as f64 / page_count as f64,
+        }
+    }
+
+    /// Bytes of free pages which VACUUM can release
+    pub fn reclaimable(&self) -> u64 {
+        self.freelist_count as u64 * self.page_size as u64
+    }
+}
+
+/// Read the header of a file, which is shorter than `HEADER_SIZE` if the file is
+pub fn read_bytes(path: &Path) -> Result<Vec<u8>> {
+    let mut header: Vec<u8> = Vec::with_capacity(HEADER_SIZE);
+
+    let mut file =
+        File::open(path).with_context(|| format!("Could not open `{}`", path.display()))?;
+
+    file.by_ref()
+        .take(HEADER_SIZE as u64)
+        .read_to_end(&mut header)
+        .with_context(|| format!("Could not read header of `{}`", path.display()))?;
+
+    Ok(header)
+}
+
+/// Read big-endian u32 at `offset`
+fn read_u32(header: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_header() {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..16].copy_from_slice(b"SQLite format 3\x00");
+        header[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        header[24..28].copy_from_slice(&42u32.to_be_bytes());
+        header[28..32].copy_from_slice(&100u32.to_be_bytes());
+        header[36..40].copy_from_slice(&25u32.to_be_bytes());
+        header[92..96].copy_from_slice(&42u32.to_be_bytes());
+
+        let parsed = Header::parse(&header).unwrap();
+        assert_eq!(
+            parsed,
+            Header {
+                page_size: 4096,
+                change_counter: 42,
+                page_count: 100,
+                freelist_count: 25,
+                version_valid_for: 42,
+            }
+        );
+        assert_eq!(parsed.free_ratio(0), 0.25);
+        assert_eq!(parsed.reclaimable(), 25 * 4096);
+
+        // Page count is stale after a write by an old SQLite, file size is used instead
+        header[16..18].copy_from_slice(&1u16.to_be_bytes());
+        header[24..28].copy_from_slice(&43u32.to_be_bytes());
+        let parsed = Header::parse(&header).unwrap();
+        assert_eq!(parsed.page_size, 65536);
+        assert_eq!(parsed.page_count(50 * 65536), 50);
+    }
+
+    #[test]
+    fn test_parse_invalid_header() {
+        assert!(Header::parse(&[0u8; HEADER_SIZE]).is_err());
+        assert!(Header::parse(b"SQLite format 3\x00").is_err());
+    }
+}
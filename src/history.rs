@@ -0,0 +1,497 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use sqlite::Connection;
+
+use crate::{
+    catalog::{Family, KnownDatabase},
+    maintenance::{self, Cleanup, Retention, Task},
+};
+
+/// Name of attached `History` in databases which refer to its URLs
+const HISTORY_SCHEMA: &str = "history";
+
+/// Temporary table of URLs in `Bookmarks` of the profile, which are kept
+const BOOKMARKED_TABLE: &str = "bookmarked_urls";
+
+/// Tasks of `--keep-history-days` and `--max-history-entries` in `History`
+///
+/// `visit_time` is in microseconds since 1601-01-01 (WebKit epoch). URLs of bookmarks, which are
+/// in another file, are kept as Firefox keeps bookmarked pages. Visits of the remaining URLs are
+/// recounted by `RECOUNT_TASKS`.
+const PRUNE_TASKS: &[Task] = &[
+    Task {
+        table: "visits",
+        action: "pruned",
+        requires: &["visits.visit_time"],
+        sql:
+            "DELETE FROM visits WHERE visit_time
+                < (CAST(strftime('%s', 'now') AS INTEGER) - :days * 86400 + 11644473600) * 1000000;",
+    },
+    Task {
+        table: "visits",
+        action: "pruned",
+        requires: &["visits.visit_time"],
+        sql: "DELETE FROM visits WHERE id IN (
+                SELECT id FROM visits ORDER BY visit_time DESC, id DESC
+                LIMIT -1 OFFSET :max_entries
+              );",
+    },
+    // URLs which were visited, but not anymore. Others are e.g. only typed.
+    Task {
+        table: "urls",
+        action: "removed",
+        requires: &["urls.last_visit_time", "visits", BOOKMARKED_TABLE],
+        sql: "DELETE FROM urls WHERE last_visit_time <> 0
+                AND NOT EXISTS (SELECT 1 FROM visits WHERE visits.url = urls.id)
+                AND url NOT IN (SELECT url FROM bookmarked_urls);",
+    },
+];
+
+/// Tasks of `History` which recount visits of URLs whose visits are pruned, after `PRUNE_TASKS`
+///
+/// URLs left without visits, e.g. bookmarked ones, get no last visit. Reloads are not counted,
+/// as by Chromium.
+const RECOUNT_TASKS: &[Task] = &[Task {
+    table: "urls",
+    action: "fixed",
+    requires: &[
+        "urls.visit_count",
+        "urls.last_visit_time",
+        "visits.transition",
+        PRUNED_TABLE,
+    ],
+    sql: "UPDATE urls SET
+            visit_count = (
+              SELECT count(*) FROM visits v WHERE v.url = urls.id AND (v.transition & 255) <> 8
+            ),
+            last_visit_time = (
+              SELECT coalesce(max(v.visit_time), 0) FROM visits v WHERE v.url = urls.id
+            )
+          WHERE id IN (SELECT url_id FROM pruned_urls);",
+}];
+
+/// Temporary table of URLs whose visits are pruned, filled by a trigger
+const PRUNED_TABLE: &str = "pruned_urls";
+
+/// Tasks of `History`, rows which refer to expired visits or URLs
+const HISTORY_TASKS: &[Task] = &[
+    Task {
+        table: "visit_source",
+        action: "removed",
+        requires: &["visit_source", "visits"],
+        sql: "DELETE FROM visit_source WHERE id NOT IN (SELECT id FROM visits);",
+    },
+    Task {
+        table: "content_annotations",
+        action: "removed",
+        requires: &["content_annotations", "visits"],
+        sql: "DELETE FROM content_annotations WHERE visit_id NOT IN (SELECT id FROM visits);",
+    },
+    Task {
+        table: "context_annotations",
+        action: "removed",
+        requires: &["context_annotations", "visits"],
+        sql: "DELETE FROM context_annotations WHERE visit_id NOT IN (SELECT id FROM visits);",
+    },
+    Task {
+        table: "clusters_and_visits",
+        action: "removed",
+        requires: &["clusters_and_visits", "visits"],
+        sql: "DELETE FROM clusters_and_visits WHERE visit_id NOT IN (SELECT id FROM visits);",
+    },
+    Task {
+        table: "segments",
+        action: "removed",
+        requires: &["segments", "urls"],
+        sql: "DELETE FROM segments WHERE url_id NOT IN (SELECT id FROM urls);",
+    },
+    Task {
+        table: "segment_usage",
+        action: "removed",
+        requires: &["segment_usage", "segments"],
+        sql: "DELETE FROM segment_usage WHERE segment_id NOT IN (SELECT id FROM segments);",
+    },
+    Task {
+        table: "keyword_search_terms",
+        action: "removed",
+        requires: &["keyword_search_terms", "urls"],
+        sql: "DELETE FROM keyword_search_terms WHERE url_id NOT IN (SELECT id FROM urls);",
+    },
+];
+
+/// Tasks of `Favicons`, icons of pages which are neither in `urls` of `History` nor bookmarked
+const FAVICONS_TASKS: &[Task] = &[
+    Task {
+        table: "icon_mapping",
+        action: "removed",
+        requires: &["icon_mapping", "urls", BOOKMARKED_TABLE],
+        sql: "DELETE FROM icon_mapping WHERE page_url NOT IN (SELECT url FROM urls)
+                AND page_url NOT IN (SELECT url FROM bookmarked_urls);",
+    },
+    Task {
+        table: "favicon_bitmaps",
+        action: "removed",
+        requires: &["favicon_bitmaps", "icon_mapping"],
+        sql: "DELETE FROM favicon_bitmaps WHERE icon_id NOT IN (SELECT icon_id FROM icon_mapping);",
+    },
+    Task {
+        table: "favicons",
+        action: "removed",
+        requires: &["favicons", "icon_mapping"],
+        sql: "DELETE FROM favicons WHERE id NOT IN (SELECT icon_id FROM icon_mapping);",
+    },
+];
+
+/// Tasks of `Top Sites`
+const TOP_SITES_TASKS: &[Task] = &[Task {
+    table: "top_sites",
+    action: "removed",
+    requires: &["top_sites", "urls"],
+    sql: "DELETE FROM top_sites WHERE url NOT IN (SELECT url FROM urls);",
+}];
+
+/// Tasks of `Shortcuts`
+const SHORTCUTS_TASKS: &[Task] = &[Task {
+    table: "omni_box_shortcuts",
+    action: "removed",
+    requires: &["omni_box_shortcuts", "urls"],
+    sql: "DELETE FROM omni_box_shortcuts WHERE url NOT IN (SELECT url FROM urls);",
+}];
+
+/// Tasks of `Network Action Predictor`
+const PREDICTOR_TASKS: &[Task] = &[Task {
+    table: "network_action_predictor",
+    action: "removed",
+    requires: &["network_action_predictor", "urls"],
+    sql: "DELETE FROM network_action_predictor WHERE url NOT IN (SELECT url FROM urls);",
+}];
+
+/// Check whether a database is maintained by `--history-maintenance`
+pub fn is_history(known: &KnownDatabase) -> bool {
+    known.family == Family::Chromium
+        && matches!(
+            known.name,
+            "History" | "Favicons" | "Top Sites" | "Shortcuts" | "Network Action Predictor"
+        )
+}
+
+/// Prune visits and remove rows which refer to expired visits or URLs in one transaction
+///
+/// `history` - `History` of the profile. Other databases refer to its `urls` by URL,
+/// so it is attached read-only. Their tasks are skipped if it does not exist.
+/// Bookmarks are read from `Bookmarks` next to it.
+pub fn maintain(
+    connection: &Connection,
+    known: &KnownDatabase,
+    history: &Path,
+    retention: &Retention,
+) -> Result<Vec<Cleanup>> {
+    let tasks: &[Task] = match known.name {
+        "History" => {
+            let bookmarks = history.with_file_name("Bookmarks");
+            if !retention.applies_to(known) || !maintenance::exists(connection, "visits")? {
+                return run_with_bookmarked(connection, &bookmarks, HISTORY_TASKS, &[]);
+            }
+
+            // Record URLs of pruned visits to recount their visits
+            connection.execute(format!(
+                "CREATE TEMP TABLE {PRUNED_TABLE} (url_id INTEGER PRIMARY KEY);
+                 CREATE TEMP TRIGGER {PRUNED_TABLE}_afterdelete
+                 AFTER DELETE ON main.visits BEGIN
+                   INSERT OR IGNORE INTO {PRUNED_TABLE} VALUES (OLD.url);
+                 END;"
+            ))?;
+            let result = run_with_bookmarked(
+                connection,
+                &bookmarks,
+                PRUNE_TASKS.iter().chain(RECOUNT_TASKS).chain(HISTORY_TASKS),
+                &retention.parameters(),
+            );
+            connection.execute(format!(
+                "DROP TRIGGER temp.{PRUNED_TABLE}_afterdelete; DROP TABLE temp.{PRUNED_TABLE};"
+            ))?;
+            return result;
+        }
+        "Favicons" => FAVICONS_TASKS,
+        "Top Sites" => TOP_SITES_TASKS,
+        "Shortcuts" => SHORTCUTS_TASKS,
+        "Network Action Predictor" => PREDICTOR_TASKS,
+        _ => return Ok(Vec::new()),
+    };
+
+    if !history.exists() {
+        return Ok(Vec::new());
+    }
+
+    // Only read by the tasks, `urls` without schema is found in the attached database
+    let mut statement = connection.prepare(format!("ATTACH DATABASE ? AS {HISTORY_SCHEMA};"))?;
+    statement.bind((1, read_only_uri(history).as_str()))?;
+    statement
+        .next()
+        .with_context(|| format!("Failed to attach `{}`", history.display()))?;
+    drop(statement);
+
+    let result = run_with_bookmarked(connection, &history.with_file_name("Bookmarks"), tasks, &[]);
+    connection.execute(format!("DETACH DATABASE {HISTORY_SCHEMA};"))?;
+    result
+}
+
+/// Run tasks with the temporary table of bookmarked URLs
+fn run_with_bookmarked<'a>(
+    connection: &Connection,
+    bookmarks: &Path,
+    tasks: impl IntoIterator<Item = &'a Task>,
+    parameters: &[(&str, Option<i64>)],
+) -> Result<Vec<Cleanup>> {
+    let result = create_bookmarked(connection, bookmarks)
+        .and_then(|_| maintenance::run(connection, tasks, parameters));
+    connection.execute(format!("DROP TABLE IF EXISTS temp.{BOOKMARKED_TABLE};"))?;
+    result
+}
+
+/// URI which opens a database read-only, the connection must be opened with URI filenames
+fn read_only_uri(path: &Path) -> String {
+    let mut uri = String::from("file:");
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '?' => uri.push_str("%3f"),
+            '#' => uri.push_str("%23"),
+            '%' => uri.push_str("%25"),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?mode=ro");
+    uri
+}
+
+/// Create temporary table of URLs in Chromium's `Bookmarks`, empty if it does not exist
+fn create_bookmarked(connection: &Connection, bookmarks: &Path) -> Result<()> {
+    let mut urls: Vec<String> = Vec::new();
+    if bookmarks.exists() {
+        let root: Value = fs::read(bookmarks)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice(&data)?))
+            .with_context(|| format!("Failed to read bookmarks `{}`", bookmarks.display()))?;
+        collect_urls(&root, &mut urls);
+    }
+
+    connection.execute(format!(
+        "CREATE TEMP TABLE {BOOKMARKED_TABLE} (url TEXT PRIMARY KEY) WITHOUT ROWID;"
+    ))?;
+    let mut statement = connection.prepare(format!(
+        "INSERT OR IGNORE INTO temp.{BOOKMARKED_TABLE} VALUES (?);"
+    ))?;
+    for url in urls.iter() {
+        statement.reset()?;
+        statement.bind((1, url.as_str()))?;
+        statement.next()?;
+    }
+
+    Ok(())
+}
+
+/// URLs of bookmark nodes, which are in `children` of folders under `roots`
+fn collect_urls(node: &Value, urls: &mut Vec<String>) {
+    match node {
+        Value::Object(fields) => {
+            if fields.get("type").and_then(Value::as_str) == Some("url") {
+                if let Some(url) = fields.get("url").and_then(Value::as_str) {
+                    urls.push(url.to_string());
+                }
+            }
+            fields.values().for_each(|value| collect_urls(value, urls));
+        }
+        Value::Array(items) => items.iter().for_each(|item| collect_urls(item, urls)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog;
+    use pretty_assertions::assert_eq;
+    use sqlite::OpenFlags;
+
+    #[test]
+    fn test_maintain_history() {
+        let connection = sqlite::open(":memory:").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE urls (id INTEGER PRIMARY KEY, url TEXT);
+                 CREATE TABLE visits (id INTEGER PRIMARY KEY, url INTEGER);
+                 CREATE TABLE visit_source (id INTEGER PRIMARY KEY, source INTEGER);
+                 CREATE TABLE keyword_search_terms (keyword_id INTEGER, url_id INTEGER, term TEXT);
+                 INSERT INTO urls VALUES (1, 'https://example.com/');
+                 INSERT INTO visits VALUES (1, 1);
+                 INSERT INTO visit_source VALUES (1, 0), (2, 0), (3, 0);
+                 INSERT INTO keyword_search_terms VALUES (1, 1, 'kept'), (1, 2, 'expired');",
+            )
+            .unwrap();
+
+        let known = catalog::find(Path::new("History")).unwrap();
+        assert_eq!(
+            maintain(
+                &connection,
+                known,
+                Path::new("History"),
+                &Retention::default()
+            )
+            .unwrap(),
+            vec![
+                Cleanup {
+                    table: "visit_source",
+                    action: "removed",
+                    rows: 2
+                },
+                Cleanup {
+                    table: "keyword_search_terms",
+                    action: "removed",
+                    rows: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prune_history() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            tmp_dir.path().join("Bookmarks"),
+            r#"{"roots": {"other": {"type": "folder", "children": [
+                 {"type": "url", "url": "https://bookmarked.example/"}]}}}"#,
+        )
+        .unwrap();
+
+        // Visits in 1601 of a bookmarked URL and an unbookmarked one, a recent visit,
+        // and a URL with an old visit, a recent one and a recent reload
+        let connection = sqlite::open(":memory:").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE urls (id INTEGER PRIMARY KEY, url TEXT, visit_count INTEGER,
+                   last_visit_time INTEGER);
+                 CREATE TABLE visits (id INTEGER PRIMARY KEY, url INTEGER, visit_time INTEGER,
+                   transition INTEGER);
+                 INSERT INTO urls VALUES (1, 'https://bookmarked.example/', 1, 1),
+                   (2, 'https://old.example/', 1, 1),
+                   (3, 'https://recent.example/', 1,
+                     (strftime('%s', 'now') + 11644473600) * 1000000),
+                   (4, 'https://revisited.example/', 2,
+                     (strftime('%s', 'now') + 11644473600) * 1000000);
+                 INSERT INTO visits VALUES (1, 1, 1, 0), (2, 2, 1, 0),
+                   (3, 3, (strftime('%s', 'now') + 11644473600) * 1000000, 0),
+                   (4, 4, 1, 0),
+                   (5, 4, (strftime('%s', 'now') - 60 + 11644473600) * 1000000, 1),
+                   (6, 4, (strftime('%s', 'now') + 11644473600) * 1000000, 8);",
+            )
+            .unwrap();
+
+        let known = catalog::find(Path::new("History")).unwrap();
+        let retention = Retention {
+            days: Some(30),
+            max_entries: None,
+        };
+        assert_eq!(
+            maintain(
+                &connection,
+                known,
+                &tmp_dir.path().join("History"),
+                &retention
+            )
+            .unwrap(),
+            vec![
+                Cleanup {
+                    table: "visits",
+                    action: "pruned",
+                    rows: 3
+                },
+                Cleanup {
+                    table: "urls",
+                    action: "removed",
+                    rows: 1
+                },
+                Cleanup {
+                    table: "urls",
+                    action: "fixed",
+                    rows: 2
+                },
+            ]
+        );
+
+        let mut statement = connection
+            .prepare(
+                "SELECT id, visit_count,
+                   last_visit_time = (SELECT max(visit_time) FROM visits WHERE url = urls.id)
+                 FROM urls ORDER BY id;",
+            )
+            .unwrap();
+        let mut urls: Vec<(i64, i64, Option<i64>)> = Vec::new();
+        while let sqlite::State::Row = statement.next().unwrap() {
+            urls.push((
+                statement.read::<i64, _>(0).unwrap(),
+                statement.read::<i64, _>(1).unwrap(),
+                statement.read::<Option<i64>, _>(2).unwrap(),
+            ));
+        }
+        assert_eq!(urls, vec![(1, 0, None), (3, 1, Some(1)), (4, 1, Some(1))]);
+
+        let mut statement = connection
+            .prepare("SELECT last_visit_time FROM urls WHERE id = 1;")
+            .unwrap();
+        statement.next().unwrap();
+        assert_eq!(statement.read::<i64, _>(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_maintain_favicons() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let history = tmp_dir.path().join("History");
+        sqlite::open(&history)
+            .unwrap()
+            .execute(
+                "CREATE TABLE urls (id INTEGER PRIMARY KEY, url TEXT);
+                 INSERT INTO urls VALUES (1, 'https://visited.example/');",
+            )
+            .unwrap();
+        fs::write(
+            tmp_dir.path().join("Bookmarks"),
+            r#"{"roots": {"bookmark_bar": {"type": "folder", "children": [
+                 {"type": "url", "url": "https://bookmarked.example/"}]}}}"#,
+        )
+        .unwrap();
+
+        let flags = OpenFlags::new().with_create().with_read_write().with_uri();
+        let connection = Connection::open_with_flags(":memory:", flags).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE icon_mapping (id INTEGER PRIMARY KEY, page_url TEXT, icon_id INTEGER);
+                 CREATE TABLE favicons (id INTEGER PRIMARY KEY);
+                 INSERT INTO icon_mapping VALUES (1, 'https://visited.example/', 1),
+                   (2, 'https://bookmarked.example/', 2), (3, 'https://expired.example/', 3);
+                 INSERT INTO favicons VALUES (1), (2), (3);",
+            )
+            .unwrap();
+
+        let known = catalog::find(Path::new("Favicons")).unwrap();
+        assert_eq!(
+            maintain(&connection, known, &history, &Retention::default()).unwrap(),
+            vec![
+                Cleanup {
+                    table: "icon_mapping",
+                    action: "removed",
+                    rows: 1
+                },
+                Cleanup {
+                    table: "favicons",
+                    action: "removed",
+                    rows: 1
+                },
+            ]
+        );
+
+        // `History` is attached read-only and detached afterwards
+        assert!(connection.execute("SELECT * FROM history.urls;").is_err());
+    }
+}
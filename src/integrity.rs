@@ -0,0 +1,200 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use anyhow::{Context, Result};
+use sqlite::{Connection, State, Value};
+
+use crate::{args::VerifyMode, common::quote_identifier};
+
+/// Logical content of a database used to compare it before and after vacuum
+#[derive(Debug, Default, PartialEq)]
+pub struct Signature {
+    /// SQL of schema objects, ordered by type and name
+    pub schema: Vec<String>,
+    pub user_version: i64,
+    pub application_id: i64,
+
+    /// Number of rows of each table
+    pub row_counts: Vec<(String, i64)>,
+
+    /// Ordered content hash of each table, only for `VerifyMode::Hash`
+    pub hashes: Option<Vec<(String, u64)>>,
+}
+
+/// Run `PRAGMA integrity_check` or `PRAGMA quick_check` on a database.
+///
+/// Return the list of problems reported by SQLite, empty if the database passes.
+///
+/// See: https://www.sqlite.org/pragma.html#pragma_integrity_check
+pub fn integrity_check(connection: &Connection, quick: bool) -> Result<Vec<String>> {
+    let pragma = if quick {
+        "PRAGMA quick_check;"
+    } else {
+        "PRAGMA integrity_check;"
+    };
+
+    let mut problems: Vec<String> = Vec::new();
+    let result = connection.iterate(pragma, |pairs| {
+        for &(_, value) in pairs.iter() {
+            match value {
+                Some("ok") => {}
+                Some(problem) => problems.push(problem.to_string()),
+                None => {}
+            }
+        }
+        true
+    });
+
+    match result {
+        Ok(()) => Ok(problems),
+        // A badly malformed database cannot be checked at all
+        Err(err) if is_corrupt(&err) => {
+            problems.push(err.to_string());
+            Ok(problems)
+        }
+        Err(err) => Err(err).with_context(|| format!("Failed to run `{pragma}`")),
+    }
+}
+
+/// Check whether an error is caused by a malformed database, `SQLITE_CORRUPT`
+pub fn is_corrupt(err: &sqlite::Error) -> bool {
+    err.code.is_some_and(|code| code & 0xff == 11)
+}
+
+impl Signature {
+    /// Read signature of a database
+    pub fn read(connection: &Connection, mode: VerifyMode) -> Result<Self> {
+        let mut signature = Signature::default();
+
+        let mut statement = connection
+            .prepare("SELECT type, name, sql FROM sqlite_schema ORDER BY type, name;")
+            .context("Failed to read schema")?;
+        let mut tables: Vec<String> = Vec::new();
+        while let State::Row = statement.next()? {
+            let object_type = statement.read::<String, _>(0)?;
+            let name = statement.read::<String, _>(1)?;
+            let sql = statement.read::<Option<String>, _>(2)?.unwrap_or_default();
+            // Content of a virtual table is kept in its shadow tables, which are compared instead
+            let is_virtual = sql.to_uppercase().starts_with("CREATE VIRTUAL TABLE");
+            signature
+                .schema
+                .push(format!("{object_type} {name}: {sql}"));
+            if object_type == "table" && !is_virtual {
+                tables.push(name);
+            }
+        }
+        drop(statement);
+
+        signature.user_version = read_integer(connection, "PRAGMA user_version;")?;
+        signature.application_id = read_integer(connection, "PRAGMA application_id;")?;
+
+        for table in tables.iter() {
+            let count = read_integer(
+                connection,
+                &format!("SELECT count(*) FROM {};", quote_identifier(table)),
+            )
+            .with_context(|| format!("Failed to count rows of table `{table}`"))?;
+            signature.row_counts.push((table.clone(), count));
+        }
+
+        if mode == VerifyMode::Hash {
+            let mut hashes: Vec<(String, u64)> = Vec::new();
+            for table in tables.iter() {
+                let hash = table_hash(connection, table)
+                    .with_context(|| format!("Failed to hash content of table `{table}`"))?;
+                hashes.push((table.clone(), hash));
+            }
+            signature.hashes = Some(hashes);
+        }
+
+        Ok(signature)
+    }
+
+    /// Describe every difference between two signatures, empty if they are identical
+    pub fn compare(&self, other: &Signature) -> Vec<String> {
+        let mut mismatches: Vec<String> = Vec::new();
+
+        if self.schema != other.schema {
+            mismatches.push("schema differs".to_string());
+        }
+
+        if self.user_version != other.user_version {
+            mismatches.push(format!(
+                "user_version {} != {}",
+                self.user_version, other.user_version
+            ));
+        }
+
+        if self.application_id != other.application_id {
+            mismatches.push(format!(
+                "application_id {} != {}",
+                self.application_id, other.application_id
+            ));
+        }
+
+        for (table, count) in self.row_counts.iter() {
+            match other.row_counts.iter().find(|(t, _)| t == table) {
+                Some((_, other_count)) if other_count == count => {}
+                Some((_, other_count)) => {
+                    mismatches.push(format!("table `{table}` has {count} != {other_count} rows"))
+                }
+                None => mismatches.push(format!("table `{table}` is missing")),
+            }
+        }
+
+        if let (Some(hashes), Some(other_hashes)) = (&self.hashes, &other.hashes) {
+            for (table, hash) in hashes.iter() {
+                if other_hashes.iter().any(|(t, h)| t == table && h != hash) {
+                    mismatches.push(format!("content of table `{table}` differs"));
+                }
+            }
+        }
+
+        mismatches
+    }
+}
+
+/// Read an integer from the first column of the first row
+fn read_integer(connection: &Connection, query: &str) -> Result<i64> {
+    let mut statement = connection.prepare(query)?;
+    match statement.next()? {
+        State::Row => Ok(statement.read::<i64, _>(0)?),
+        State::Done => Ok(0),
+    }
+}
+
+/// Hash all rows of a table ordered by every column, independent of rowid
+fn table_hash(connection: &Connection, table: &str) -> Result<u64> {
+    let column_count = connection
+        .prepare(format!(
+            "SELECT * FROM {} LIMIT 0;",
+            quote_identifier(table)
+        ))?
+        .column_count();
+    let order_by = (1..=column_count)
+        .map(|column| column.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let mut statement = connection.prepare(format!(
+        "SELECT * FROM {} ORDER BY {order_by};",
+        quote_identifier(table)
+    ))?;
+
+    let mut hasher = DefaultHasher::new();
+    while let State::Row = statement.next()? {
+        for column in 0..column_count {
+            match statement.read::<Value, _>(column)? {
+                Value::Binary(value) => (0u8, value).hash(&mut hasher),
+                Value::Float(value) => (1u8, value.to_bits()).hash(&mut hasher),
+                Value::Integer(value) => (2u8, value).hash(&mut hasher),
+                Value::String(value) => (3u8, value).hash(&mut hasher),
+                Value::Null => 4u8.hash(&mut hasher),
+            }
+        }
+    }
+
+    Ok(hasher.finish())
+}
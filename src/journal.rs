@@ -0,0 +1,208 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use tracing::{debug, info};
+
+use crate::common::{remove_wal, staging_path, state_dir};
+
+/// Journal of a defrag run at `$XDG_STATE_HOME/browser-defrag/journal`
+///
+/// Each line is `<ACTION> <VALUE>`:
+///
+/// * `browser <NAME>` - Browser of the run
+/// * `begin <PATH>` - Database is being defragged
+/// * `work <PATH>` - Temporary directory of the database
+/// * `replace <PATH>` - Staging file of the database is complete, it is being renamed over it
+/// * `done <PATH>` - Database is finished
+///
+/// The journal is removed when a run finishes, a remaining journal belongs to
+/// an interrupted or crashed run.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+
+    /// Lock of the journal, held until the run ends
+    _lock: RunLock,
+
+    /// Databases finished by a previous run
+    done: Vec<PathBuf>,
+}
+
+impl Journal {
+    /// Start journal of a new run
+    ///
+    /// `lock` - Lock taken before recovering the previous run
+    ///
+    /// `done` - Databases finished by a previous run, to be skipped
+    pub fn start(lock: RunLock, browser_name: &str, done: Vec<PathBuf>) -> Result<Self> {
+        let path = journal_path()?;
+        fs::create_dir_all(path.parent().unwrap())
+            .with_context(|| format!("Failed to create directory of `{}`", path.display()))?;
+
+        let mut file = File::create(&path)
+            .with_context(|| format!("Failed to create journal `{}`", path.display()))?;
+        writeln!(file, "browser {browser_name}")?;
+        for db in done.iter() {
+            writeln!(file, "done {}", db.display())?;
+        }
+        file.sync_all()?;
+
+        Ok(Self {
+            path,
+            file,
+            _lock: lock,
+            done,
+        })
+    }
+
+    /// Check whether a database was finished by a previous run
+    pub fn is_done(&self, db: &Path) -> bool {
+        self.done.iter().any(|done| done == db)
+    }
+
+    pub fn begin(&self, db: &Path) -> Result<()> {
+        self.write("begin", db)
+    }
+
+    pub fn work_dir(&self, dir: &Path) -> Result<()> {
+        self.write("work", dir)
+    }
+
+    pub fn replace(&self, db: &Path) -> Result<()> {
+        self.write("replace", db)
+    }
+
+    pub fn done(&self, db: &Path) -> Result<()> {
+        self.write("done", db)
+    }
+
+    /// Remove journal when a run finishes
+    pub fn finish(&self) -> Result<()> {
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove journal `{}`", self.path.display()))
+    }
+
+    fn write(&self, action: &str, path: &Path) -> Result<()> {
+        let mut file = &self.file;
+        writeln!(file, "{action} {}", path.display())
+            .and_then(|_| file.sync_data())
+            .with_context(|| format!("Failed to write journal `{}`", self.path.display()))
+    }
+}
+
+/// Location of journal
+pub fn journal_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("journal"))
+}
+
+/// Exclusive lock of the journal, only one run at a time may recover and write it
+///
+/// The lock is `flock` on `journal.lock`, which is released by the kernel when the process exits.
+#[derive(Debug)]
+pub struct RunLock {
+    /// Locked file, the lock is released when it is closed
+    _file: File,
+}
+
+impl RunLock {
+    /// Take the lock, fail if another run holds it
+    pub fn acquire() -> Result<Self> {
+        let path = state_dir()?.join("journal.lock");
+        fs::create_dir_all(path.parent().unwrap())
+            .with_context(|| format!("Failed to create directory of `{}`", path.display()))?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open `{}`", path.display()))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                bail!("Another run of browser-defrag is in progress");
+            }
+            return Err(err).with_context(|| format!("Failed to lock `{}`", path.display()));
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// Clean up leftovers of an interrupted or crashed run, the caller must hold `RunLock`
+///
+/// Return databases finished by that run if it was a run of `browser_name`.
+pub fn recover(_lock: &RunLock, browser_name: &str) -> Result<Vec<PathBuf>> {
+    let path = journal_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let journal = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read journal `{}`", path.display()))?;
+    info!("Found journal of an unfinished run `{}`", path.display());
+
+    let mut same_browser = false;
+    let mut begun: Vec<PathBuf> = Vec::new();
+    let mut work_dirs: Vec<PathBuf> = Vec::new();
+    let mut replacing: Vec<PathBuf> = Vec::new();
+    let mut done: Vec<PathBuf> = Vec::new();
+    for line in journal.lines() {
+        let Some((action, value)) = line.split_once(' ') else {
+            continue;
+        };
+
+        match action {
+            "browser" => same_browser = value == browser_name,
+            "begin" => begun.push(PathBuf::from(value)),
+            "work" => work_dirs.push(PathBuf::from(value)),
+            "replace" => replacing.push(PathBuf::from(value)),
+            "done" => {
+                replacing.retain(|db| db.as_os_str() != value);
+                done.push(PathBuf::from(value));
+            }
+            _ => {}
+        }
+    }
+
+    // Staging file was renamed over the database, but the WAL of the original was not removed
+    for db in replacing.iter() {
+        if !staging_path(db).exists() {
+            debug!("Remove WAL of the original of `{}`", db.display());
+            remove_wal(db)?;
+        }
+    }
+
+    // Replacement was not finished, the original is intact
+    for db in begun.iter() {
+        let staging = staging_path(db);
+        if staging.exists() {
+            debug!("Remove leftover `{}`", staging.display());
+            fs::remove_file(&staging)
+                .with_context(|| format!("Failed to remove `{}`", staging.display()))?;
+        }
+    }
+
+    for work_dir in work_dirs.iter() {
+        if work_dir.exists() {
+            debug!("Remove leftover `{}`", work_dir.display());
+            fs::remove_dir_all(work_dir)
+                .with_context(|| format!("Failed to remove `{}`", work_dir.display()))?;
+        }
+    }
+
+    fs::remove_file(&path)
+        .with_context(|| format!("Failed to remove journal `{}`", path.display()))?;
+
+    if !same_browser {
+        done.clear();
+    }
+
+    Ok(done)
+}
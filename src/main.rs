@@ -1,26 +1,58 @@
 mod args;
+mod backup;
+mod catalog;
 mod chromium;
 mod common;
+mod cookies;
 mod defrag;
+mod filesystem;
 mod firefox;
+mod fts;
+mod functions;
+mod header;
+mod history;
+mod integrity;
+mod interrupt;
+mod journal;
+mod maintenance;
+mod mozlz4;
+mod places;
+mod process;
 mod report;
+mod salvage;
+mod session;
+mod site_data;
+mod timeout;
 mod unknown;
 
 use std::{
+    env,
     io::{self, Write},
+    path::PathBuf,
     process::ExitCode,
+    time::Duration,
 };
 
-use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use tracing::{debug, error};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{CommandFactory, FromArgMatches};
+use tracing::{debug, error, info};
 use tracing_subscriber::EnvFilter;
 
 use crate::{
-    args::Arguments,
-    defrag::{Browser, Config, Defragment},
+    args::{Arguments, Engine},
+    backup::Backup,
+    defrag::{Browser, Config, DefragOptions, Defragment, Profile},
+    journal::{Journal, RunLock},
+    maintenance::Retention,
+    session::SessionLimits,
 };
 
+/// Database listing function of a browser
+type ListDatabases = fn(Config) -> Result<Vec<Profile>>;
+
+/// How long `--close` waits for the browser to save its session and exit, unless `--wait` is given
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+
 fn run() -> Result<()> {
     let filter =
         EnvFilter::try_from_default_env().unwrap_or(EnvFilter::try_new("browser_defrag=warn")?);
@@ -32,48 +64,173 @@ fn run() -> Result<()> {
         .map_err(|err| anyhow!("{err:#}"))
         .context("Failed to initialize tracing subscriber")?;
 
-    let arguments = Arguments::parse();
+    let matches = Arguments::command().get_matches();
+    let arguments = Arguments::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
     debug!("Run with {:?}", arguments);
 
-    match arguments.browser {
-        args::BrowserType::Firefox => {
-            let config = Config {
-                max_depth: arguments.max_depth,
-                profile_path: None,
-            };
-
-            let mut browser = Browser::new("Firefox");
-            browser.list_databases(firefox::list_db, config)?;
-            browser.defrag(arguments.dry_run)?;
-            let mut stdout = io::BufWriter::new(io::stdout().lock());
-            writeln!(stdout, "{browser}")?;
+    let (browser_name, list_db, profile_path): (&str, ListDatabases, Option<PathBuf>) =
+        match arguments.browser {
+            args::BrowserType::Firefox => ("Firefox", firefox::list_db, None),
+            args::BrowserType::Chromium => ("Chromium", chromium::list_db, None),
+            args::BrowserType::Unknown { profile_path } => {
+                ("Unknown", unknown::list_db, Some(profile_path))
+            }
+            args::BrowserType::Restore {
+                run_id,
+                profile,
+                db,
+            } => {
+                let ignored = args::restore_ignored(&matches);
+                if !ignored.is_empty() {
+                    bail!("`{}` cannot be used with restore", ignored.join("`, `"));
+                }
+
+                // Do not restore databases which a run is replacing
+                let _lock = RunLock::acquire()?;
+                let restored = backup::restore(
+                    run_id.as_deref(),
+                    profile.as_deref(),
+                    db.as_deref(),
+                    arguments.quick,
+                    arguments.dry_run,
+                )?;
+                let action = if arguments.dry_run {
+                    "Would restore"
+                } else {
+                    "Restored"
+                };
+                let mut stdout = io::BufWriter::new(io::stdout().lock());
+                for path in restored.iter() {
+                    writeln!(stdout, "{action} `{}`", path.display())?;
+                }
+                return Ok(());
+            }
+        };
+
+    let backup = if arguments.backup && !arguments.dry_run {
+        Some(Backup::new(
+            browser_name,
+            arguments.backup_keep,
+            arguments
+                .backup_max_age
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        )?)
+    } else {
+        None
+    };
+
+    // SQLite creates VACUUM's temporary database in `SQLITE_TMPDIR`
+    if let Some(work_dir) = arguments.work_dir.as_ref() {
+        if !work_dir.is_dir() {
+            bail!("Work directory `{}` is not exist", work_dir.display());
+        }
+        env::set_var("SQLITE_TMPDIR", work_dir);
+    }
+
+    // Browser must exit before its databases are defragged
+    let mut _relaunch: Option<process::Relaunch> = None;
+    if !arguments.dry_run {
+        let timeout = arguments.wait.flatten().map(Duration::from_secs);
+        if arguments.close {
+            if browser_name == "Unknown" {
+                bail!("Cannot close an unknown browser, use `--wait` instead");
+            }
+            let launches = process::close(browser_name, timeout.or(Some(CLOSE_TIMEOUT)))?;
+            if arguments.relaunch {
+                _relaunch = Some(process::Relaunch(launches));
+            }
+        } else if arguments.wait.is_some() {
+            process::wait_for_exit(browser_name, timeout)?;
+        }
+    }
+
+    // Clean up leftovers of an interrupted or crashed run
+    let journal = if !arguments.dry_run {
+        let lock = RunLock::acquire()?;
+        let done = journal::recover(&lock, browser_name)?;
+        if !arguments.resume && !done.is_empty() {
+            info!("Start a new run, ignore databases finished by the previous run");
         }
-        args::BrowserType::Chromium => {
-            let config = Config {
-                max_depth: arguments.max_depth,
-                profile_path: None,
-            };
-
-            let mut browser = Browser::new("Chromium");
-            browser.list_databases(chromium::list_db, config)?;
-            browser.defrag(arguments.dry_run)?;
-            let mut stdout = io::BufWriter::new(io::stdout().lock());
-            writeln!(stdout, "{browser}")?;
+        interrupt::install_handler()?;
+        Some(Journal::start(
+            lock,
+            browser_name,
+            if arguments.resume { done } else { Vec::new() },
+        )?)
+    } else {
+        None
+    };
+
+    let options = DefragOptions {
+        dry_run: arguments.dry_run,
+        quick_check: arguments.quick,
+        verify: arguments.verify,
+        statistics: arguments.statistics,
+        analysis_limit: arguments.analysis_limit,
+        fts: arguments.fts,
+        places_maintenance: arguments.places_maintenance,
+        history_maintenance: arguments.history_maintenance,
+        purge_expired_cookies: arguments.purge_expired_cookies,
+        sessions: arguments.compact_sessions.then_some(SessionLimits {
+            closed_tabs: arguments.max_closed_tabs,
+            closed_windows: arguments.max_closed_windows,
+            tab_history: arguments.max_tab_history,
+        }),
+        purge_stale_site_data: arguments.purge_stale_site_data,
+        retention: Retention {
+            days: arguments.keep_history_days,
+            max_entries: arguments.max_history_entries,
+        },
+        auto_vacuum: arguments.set_auto_vacuum,
+        page_size: arguments.page_size,
+        min_free_ratio: arguments.min_free_ratio,
+        min_reclaim: arguments.min_reclaim,
+        engine: if arguments.in_place {
+            Engine::InPlace
+        } else {
+            arguments.engine
+        },
+        backup,
+        work_dir: arguments.work_dir,
+        journal,
+        salvage: arguments.salvage,
+        allow_network_fs: arguments.allow_network_fs,
+        db_timeout: arguments.db_timeout.map(Duration::from_secs),
+    };
+
+    let config = Config {
+        max_depth: arguments.max_depth,
+        profile_path,
+    };
+
+    let mut browser = Browser::new(browser_name);
+    browser.list_databases(list_db, config)?;
+    if let Err(err) = browser.defrag(&options) {
+        // The run did not start, nothing to resume
+        if let Some(journal) = options.journal.as_ref() {
+            journal.finish()?;
         }
-        args::BrowserType::Unknown { profile_path } => {
-            let config = Config {
-                max_depth: arguments.max_depth,
-                profile_path: Some(profile_path),
-            };
-
-            let mut browser = Browser::new("Unknown");
-            browser.list_databases(unknown::list_db, config)?;
-            browser.defrag(arguments.dry_run)?;
-            let mut stdout = io::BufWriter::new(io::stdout().lock());
-            writeln!(stdout, "{browser}")?;
+        return Err(err);
+    }
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    writeln!(stdout, "{browser}")?;
+
+    if let Some(backup) = options.backup.as_ref() {
+        if backup.has_files() {
+            writeln!(
+                stdout,
+                "Backup `{}` is kept in `{}`",
+                backup.run_id,
+                backup.run_dir().display()
+            )?;
         }
     }
 
+    if interrupt::is_interrupted() {
+        stdout.flush()?;
+        bail!("Interrupted, run again with `--resume` to continue with remaining databases");
+    }
+
     Ok(())
 }
 
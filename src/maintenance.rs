@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use sqlite::{Connection, State};
+
+use crate::catalog::KnownDatabase;
+
+/// Maintenance task which cleans up a table with a single statement
+pub struct Task {
+    /// Table changed by the task
+    pub table: &'static str,
+
+    /// What happens to changed rows, e.g. `removed` or `fixed`, `pruned` is only for visits
+    pub action: &'static str,
+
+    /// Tables or `table.column`s which must exist, they differ between browser versions
+    pub requires: &'static [&'static str],
+
+    /// Statement, which may use parameters of `Retention`
+    pub sql: &'static str,
+}
+
+/// Limits of history, see `--keep-history-days` and `--max-history-entries`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    /// Visits older than this number of days are removed
+    pub days: Option<u32>,
+
+    /// Only this number of most recent visits is kept
+    pub max_entries: Option<u32>,
+}
+
+impl Retention {
+    /// Check whether a limit is set
+    pub fn is_set(&self) -> bool {
+        self.days.is_some() || self.max_entries.is_some()
+    }
+
+    /// Check whether a database is pruned, i.e. it has visits and a limit is set
+    pub fn applies_to(&self, known: &KnownDatabase) -> bool {
+        self.is_set() && known.has_visits()
+    }
+
+    /// Parameters of tasks, a task which uses a limit that is not set is skipped
+    pub fn parameters(&self) -> [(&'static str, Option<i64>); 2] {
+        [
+            (":days", self.days.map(i64::from)),
+            (":max_entries", self.max_entries.map(i64::from)),
+        ]
+    }
+}
+
+/// Rows of a table changed by maintenance
+#[derive(Debug, PartialEq)]
+pub struct Cleanup {
+    pub table: &'static str,
+    pub action: &'static str,
+    pub rows: usize,
+}
+
+/// Run tasks whose tables exist in one transaction
+///
+/// Return rows changed by each table and action, in order of tasks.
+pub fn run<'a>(
+    connection: &Connection,
+    tasks: impl IntoIterator<Item = &'a Task>,
+    parameters: &[(&str, Option<i64>)],
+) -> Result<Vec<Cleanup>> {
+    connection.execute("BEGIN IMMEDIATE;")?;
+    match run_tasks(connection, tasks, parameters) {
+        Ok(cleanups) => {
+            connection.execute("COMMIT;")?;
+            Ok(cleanups)
+        }
+        Err(err) => {
+            let _ = connection.execute("ROLLBACK;");
+            Err(err)
+        }
+    }
+}
+
+fn run_tasks<'a>(
+    connection: &Connection,
+    tasks: impl IntoIterator<Item = &'a Task>,
+    parameters: &[(&str, Option<i64>)],
+) -> Result<Vec<Cleanup>> {
+    let mut cleanups: Vec<Cleanup> = Vec::new();
+    for task in tasks {
+        let mut available = true;
+        for requirement in task.requires {
+            available &= exists(connection, requirement)?;
+        }
+        if !available {
+            continue;
+        }
+
+        let mut statement = connection
+            .prepare(task.sql)
+            .with_context(|| format!("Failed to clean up table `{}`", task.table))?;
+        for (name, value) in parameters {
+            if let Some(index) = statement.parameter_index(name)? {
+                match value {
+                    Some(value) => statement.bind((index, *value))?,
+                    None => available = false,
+                }
+            }
+        }
+        if !available {
+            continue;
+        }
+
+        while let State::Row = statement
+            .next()
+            .with_context(|| format!("Failed to clean up table `{}`", task.table))?
+        {}
+        drop(statement);
+        let rows = connection.change_count();
+        if rows == 0 {
+            continue;
+        }
+
+        match cleanups
+            .iter_mut()
+            .find(|cleanup| cleanup.table == task.table && cleanup.action == task.action)
+        {
+            Some(cleanup) => cleanup.rows += rows,
+            None => cleanups.push(Cleanup {
+                table: task.table,
+                action: task.action,
+                rows,
+            }),
+        }
+    }
+
+    Ok(cleanups)
+}
+
+/// Check whether a table or a `table.column` exists, in the main or an attached database
+pub fn exists(connection: &Connection, requirement: &str) -> Result<bool> {
+    let (table, column) = match requirement.split_once('.') {
+        Some((table, column)) => (table, Some(column)),
+        None => (requirement, None),
+    };
+
+    let mut statement = connection.prepare("SELECT name FROM pragma_table_info(?);")?;
+    statement.bind((1, table))?;
+    let mut found = false;
+    while let State::Row = statement.next()? {
+        let name = statement.read::<String, _>(0)?;
+        found = column.is_none_or(|column| column.eq_ignore_ascii_case(&name));
+        if found {
+            break;
+        }
+    }
+
+    Ok(found)
+}
@@ -0,0 +1,74 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+/// Magic number at the start of mozLz4 files
+const MAGIC: &[u8; 8] = b"mozLz40\0";
+
+/// Size of magic number and little-endian u32 size of decompressed data
+const HEADER_SIZE: usize = 12;
+
+/// Check whether a file is mozLz4, e.g. Firefox's `sessionstore.jsonlz4`
+pub fn is_mozlz4_file(path: &Path) -> Result<bool> {
+    let mut magic: Vec<u8> = Vec::with_capacity(MAGIC.len());
+    File::open(path)
+        .with_context(|| format!("Could not open `{}`", path.display()))?
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .with_context(|| format!("Could not read `{}`", path.display()))?;
+
+    Ok(magic == MAGIC)
+}
+
+/// Decompress content of a mozLz4 file
+///
+/// mozLz4 is a header followed by a single LZ4 block, without the LZ4 frame format.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < HEADER_SIZE || &data[..MAGIC.len()] != MAGIC {
+        bail!("Not a mozLz4 file");
+    }
+
+    let size = u32::from_le_bytes(data[MAGIC.len()..HEADER_SIZE].try_into().unwrap()) as usize;
+    let decompressed = lz4_flex::block::decompress(&data[HEADER_SIZE..], size)
+        .context("Invalid LZ4 block of mozLz4 file")?;
+    if decompressed.len() != size {
+        bail!(
+            "Size of mozLz4 file is {} bytes, but its header says {size}",
+            decompressed.len()
+        );
+    }
+
+    Ok(decompressed)
+}
+
+/// Compress data into content of a mozLz4 file
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let Ok(size) = u32::try_from(data.len()) else {
+        bail!("Data of {} bytes is too large for mozLz4", data.len());
+    };
+
+    let compressed = lz4_flex::block::compress(data);
+    let mut output: Vec<u8> = Vec::with_capacity(HEADER_SIZE + compressed.len());
+    output.extend_from_slice(MAGIC);
+    output.extend_from_slice(&size.to_le_bytes());
+    output.extend_from_slice(&compressed);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_mozlz4() {
+        let data = br#"{"version":["sessionrestore",1],"windows":[]}"#;
+        let compressed = compress(data).unwrap();
+        assert_eq!(&compressed[..8], b"mozLz40\0");
+        assert_eq!(&compressed[8..12], &(data.len() as u32).to_le_bytes());
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        assert!(decompress(b"mozLz40\0").is_err());
+        assert!(decompress(&data[..]).is_err());
+    }
+}
@@ -0,0 +1,411 @@
+use anyhow::Result;
+use sqlite::Connection;
+
+use crate::{
+    catalog::{Family, KnownDatabase},
+    maintenance::{self, Cleanup, Retention, Task},
+};
+
+/// Tasks of `--keep-history-days` and `--max-history-entries` in `places.sqlite`
+///
+/// `visit_date` is in microseconds since Unix epoch. Pages left without visits are removed
+/// by `ORPHAN_TASKS` unless they are bookmarked, visit counts of the others by `RECOUNT_TASKS`.
+const PRUNE_TASKS: &[Task] = &[
+    Task {
+        table: "moz_historyvisits",
+        action: "pruned",
+        requires: &["moz_historyvisits.visit_date"],
+        sql: "DELETE FROM moz_historyvisits
+              WHERE visit_date < (CAST(strftime('%s', 'now') AS INTEGER) - :days * 86400) * 1000000;",
+    },
+    Task {
+        table: "moz_historyvisits",
+        action: "pruned",
+        requires: &["moz_historyvisits.visit_date"],
+        sql: "DELETE FROM moz_historyvisits WHERE id IN (
+                SELECT id FROM moz_historyvisits ORDER BY visit_date DESC, id DESC
+                LIMIT -1 OFFSET :max_entries
+              );",
+    },
+];
+
+/// Tasks of `places.sqlite`, pages are removed first to also remove what refers to them
+const ORPHAN_TASKS: &[Task] = &[
+    // Pages without visits which are not bookmarked, expired by Firefox on its own
+    Task {
+        table: "moz_places",
+        action: "removed",
+        requires: &[
+            "moz_places.foreign_count",
+            "moz_historyvisits",
+            "moz_bookmarks",
+            "moz_keywords",
+        ],
+        sql: "DELETE FROM moz_places WHERE id IN (
+                SELECT h.id FROM moz_places h
+                WHERE h.foreign_count = 0
+                  AND NOT EXISTS (SELECT 1 FROM moz_historyvisits v WHERE v.place_id = h.id)
+                  AND NOT EXISTS (SELECT 1 FROM moz_bookmarks b WHERE b.fk = h.id)
+                  AND NOT EXISTS (SELECT 1 FROM moz_keywords k WHERE k.place_id = h.id)
+              );",
+    },
+    Task {
+        table: "moz_historyvisits",
+        action: "removed",
+        requires: &["moz_historyvisits"],
+        sql: "DELETE FROM moz_historyvisits
+              WHERE place_id NOT IN (SELECT id FROM moz_places);",
+    },
+    Task {
+        table: "moz_inputhistory",
+        action: "removed",
+        requires: &["moz_inputhistory"],
+        sql: "DELETE FROM moz_inputhistory
+              WHERE place_id NOT IN (SELECT id FROM moz_places);",
+    },
+    Task {
+        table: "moz_annos",
+        action: "removed",
+        requires: &["moz_annos", "moz_anno_attributes"],
+        sql: "DELETE FROM moz_annos
+              WHERE place_id NOT IN (SELECT id FROM moz_places)
+                 OR anno_attribute_id NOT IN (SELECT id FROM moz_anno_attributes);",
+    },
+    Task {
+        table: "moz_places_metadata",
+        action: "removed",
+        requires: &["moz_places_metadata"],
+        sql: "DELETE FROM moz_places_metadata
+              WHERE place_id NOT IN (SELECT id FROM moz_places);",
+    },
+    Task {
+        table: "moz_places_metadata_search_queries",
+        action: "removed",
+        requires: &[
+            "moz_places_metadata.search_query_id",
+            "moz_places_metadata_search_queries",
+        ],
+        sql: "DELETE FROM moz_places_metadata_search_queries WHERE id NOT IN (
+                SELECT search_query_id FROM moz_places_metadata
+                WHERE search_query_id NOT NULL
+              );",
+    },
+    Task {
+        table: "moz_places_extra",
+        action: "removed",
+        requires: &["moz_places_extra"],
+        sql: "DELETE FROM moz_places_extra
+              WHERE place_id NOT IN (SELECT id FROM moz_places);",
+    },
+    Task {
+        table: "moz_historyvisits_extra",
+        action: "removed",
+        requires: &["moz_historyvisits_extra"],
+        sql: "DELETE FROM moz_historyvisits_extra
+              WHERE visit_id NOT IN (SELECT id FROM moz_historyvisits);",
+    },
+    Task {
+        table: "moz_origins",
+        action: "removed",
+        requires: &["moz_origins", "moz_places.origin_id"],
+        sql: "DELETE FROM moz_origins WHERE id NOT IN (
+                SELECT origin_id FROM moz_places WHERE origin_id NOT NULL
+              );",
+    },
+];
+
+/// Tasks of `places.sqlite` after pruning, pages which lost visits still count them
+///
+/// Visits of embeds, downloads, framed links and reloads are not counted, as by Firefox.
+const RECOUNT_TASKS: &[Task] = &[Task {
+    table: "moz_places",
+    action: "fixed",
+    requires: &[
+        "moz_places.visit_count",
+        "moz_places.last_visit_date",
+        "moz_historyvisits.visit_type",
+        PRUNED_TABLE,
+    ],
+    sql: "UPDATE moz_places SET
+            visit_count = (
+              SELECT count(*) FROM moz_historyvisits v
+              WHERE v.place_id = moz_places.id AND v.visit_type NOT IN (0, 4, 7, 8, 9)
+            ),
+            last_visit_date = (
+              SELECT max(v.visit_date) FROM moz_historyvisits v WHERE v.place_id = moz_places.id
+            )
+          WHERE id IN (SELECT place_id FROM pruned_places);",
+}];
+
+/// Temporary table of pages whose visits are pruned, filled by a trigger
+const PRUNED_TABLE: &str = "pruned_places";
+
+/// Tasks of `places.sqlite` which fix bookmarks
+const BOOKMARK_TASKS: &[Task] = &[
+    // Items whose parent does not exist or is not a folder are moved to Other Bookmarks
+    Task {
+        table: "moz_bookmarks",
+        action: "fixed",
+        requires: &["moz_bookmarks.syncChangeCounter"],
+        sql: "UPDATE moz_bookmarks SET
+                parent = (SELECT id FROM moz_bookmarks WHERE guid = 'unfiled_____'),
+                position = 2147483647,
+                syncChangeCounter = syncChangeCounter + 1
+              WHERE guid NOT IN ('root________', 'menu________', 'toolbar_____',
+                                 'unfiled_____', 'mobile______', 'tags________')
+                AND EXISTS (SELECT 1 FROM moz_bookmarks WHERE guid = 'unfiled_____')
+                AND NOT EXISTS (
+                  SELECT 1 FROM moz_bookmarks p WHERE p.id = moz_bookmarks.parent AND p.type = 2
+                );",
+    },
+    // Positions of Other Bookmarks without gaps or duplicates, after items are moved into it
+    Task {
+        table: "moz_bookmarks",
+        action: "reordered",
+        requires: &["moz_bookmarks"],
+        sql: "UPDATE moz_bookmarks SET position = ordered.new_position
+              FROM (
+                SELECT id, row_number() OVER (ORDER BY position, id) - 1 AS new_position
+                FROM moz_bookmarks
+                WHERE parent = (SELECT id FROM moz_bookmarks WHERE guid = 'unfiled_____')
+              ) AS ordered
+              WHERE moz_bookmarks.id = ordered.id
+                AND moz_bookmarks.position <> ordered.new_position;",
+    },
+];
+
+/// Tasks of `favicons.sqlite`
+///
+/// Pages of favicons are not checked against `moz_places`, which is in another database.
+const FAVICONS_TASKS: &[Task] = &[
+    Task {
+        table: "moz_icons_to_pages",
+        action: "removed",
+        requires: &["moz_icons_to_pages", "moz_pages_w_icons", "moz_icons"],
+        sql: "DELETE FROM moz_icons_to_pages
+              WHERE page_id NOT IN (SELECT id FROM moz_pages_w_icons)
+                 OR icon_id NOT IN (SELECT id FROM moz_icons);",
+    },
+    Task {
+        table: "moz_pages_w_icons",
+        action: "removed",
+        requires: &["moz_pages_w_icons", "moz_icons_to_pages"],
+        sql: "DELETE FROM moz_pages_w_icons
+              WHERE id NOT IN (SELECT page_id FROM moz_icons_to_pages);",
+    },
+    // Root icons, e.g. `/favicon.ico`, are kept for every page of their origin
+    Task {
+        table: "moz_icons",
+        action: "removed",
+        requires: &["moz_icons.root", "moz_icons_to_pages"],
+        sql: "DELETE FROM moz_icons
+              WHERE root = 0 AND id NOT IN (SELECT icon_id FROM moz_icons_to_pages);",
+    },
+];
+
+/// Check whether a database is maintained by `--places-maintenance`
+pub fn is_places(known: &KnownDatabase) -> bool {
+    known.family == Family::Gecko && matches!(known.name, "places.sqlite" | "favicons.sqlite")
+}
+
+/// Prune history, remove orphans and fix broken bookmark parents in one transaction
+///
+/// Orphans of the whole database are removed, also when only pruning, as by Firefox's expiration.
+///
+/// `fix_bookmarks` - Also fix broken bookmark parents, see `--places-maintenance`.
+pub fn maintain(
+    connection: &Connection,
+    known: &KnownDatabase,
+    retention: &Retention,
+    fix_bookmarks: bool,
+) -> Result<Vec<Cleanup>> {
+    match known.name {
+        "places.sqlite" => {
+            let bookmark_tasks: &[Task] = if fix_bookmarks { BOOKMARK_TASKS } else { &[] };
+            if !retention.applies_to(known)
+                || !maintenance::exists(connection, "moz_historyvisits")?
+            {
+                return maintenance::run(
+                    connection,
+                    ORPHAN_TASKS.iter().chain(bookmark_tasks),
+                    &[],
+                );
+            }
+
+            // Record pages of pruned visits to recount their visits
+            connection.execute(format!(
+                "CREATE TEMP TABLE {PRUNED_TABLE} (place_id INTEGER PRIMARY KEY);
+                 CREATE TEMP TRIGGER {PRUNED_TABLE}_afterdelete
+                 AFTER DELETE ON main.moz_historyvisits BEGIN
+                   INSERT OR IGNORE INTO {PRUNED_TABLE} VALUES (OLD.place_id);
+                 END;"
+            ))?;
+            let result = maintenance::run(
+                connection,
+                PRUNE_TASKS
+                    .iter()
+                    .chain(ORPHAN_TASKS)
+                    .chain(RECOUNT_TASKS)
+                    .chain(bookmark_tasks),
+                &retention.parameters(),
+            );
+            connection.execute(format!(
+                "DROP TRIGGER temp.{PRUNED_TABLE}_afterdelete; DROP TABLE temp.{PRUNED_TABLE};"
+            ))?;
+            result
+        }
+        "favicons.sqlite" => maintenance::run(connection, FAVICONS_TASKS, &[]),
+        _ => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog;
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    #[test]
+    fn test_maintain_places() {
+        let connection = sqlite::open(":memory:").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE moz_origins (id INTEGER PRIMARY KEY, host TEXT);
+                 CREATE TABLE moz_places (id INTEGER PRIMARY KEY, url TEXT,
+                   foreign_count INTEGER DEFAULT 0, origin_id INTEGER);
+                 CREATE TABLE moz_historyvisits (id INTEGER PRIMARY KEY, place_id INTEGER);
+                 CREATE TABLE moz_keywords (id INTEGER PRIMARY KEY, place_id INTEGER);
+                 CREATE TABLE moz_bookmarks (id INTEGER PRIMARY KEY, type INTEGER, fk INTEGER,
+                   parent INTEGER, position INTEGER, guid TEXT,
+                   syncChangeCounter INTEGER DEFAULT 1);
+                 INSERT INTO moz_origins VALUES (1, 'visited.example'), (2, 'orphan.example');
+                 INSERT INTO moz_places VALUES (1, 'https://visited.example/', 0, 1),
+                   (2, 'https://orphan.example/', 0, 2), (3, 'https://bookmarked.example/', 1, NULL);
+                 INSERT INTO moz_historyvisits VALUES (1, 1), (2, 42);
+                 INSERT INTO moz_bookmarks VALUES
+                   (1, 2, NULL, 0, 0, 'root________', 1),
+                   (2, 2, NULL, 1, 0, 'unfiled_____', 1),
+                   (3, 1, 3, 2, 0, 'bookmark____', 1),
+                   (4, 1, 3, 99, 0, 'lost________', 1);",
+            )
+            .unwrap();
+
+        let known = catalog::find(Path::new("places.sqlite")).unwrap();
+        assert_eq!(
+            maintain(&connection, known, &Retention::default(), true).unwrap(),
+            vec![
+                Cleanup {
+                    table: "moz_places",
+                    action: "removed",
+                    rows: 1
+                },
+                Cleanup {
+                    table: "moz_historyvisits",
+                    action: "removed",
+                    rows: 1
+                },
+                Cleanup {
+                    table: "moz_origins",
+                    action: "removed",
+                    rows: 1
+                },
+                Cleanup {
+                    table: "moz_bookmarks",
+                    action: "fixed",
+                    rows: 1
+                },
+                Cleanup {
+                    table: "moz_bookmarks",
+                    action: "reordered",
+                    rows: 1
+                },
+            ]
+        );
+
+        let mut statement = connection
+            .prepare("SELECT parent, position FROM moz_bookmarks WHERE id = 4;")
+            .unwrap();
+        statement.next().unwrap();
+        assert_eq!(statement.read::<i64, _>(0).unwrap(), 2);
+        assert_eq!(statement.read::<i64, _>(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_prune_places() {
+        let connection = sqlite::open(":memory:").unwrap();
+        // Visits in 1970 and now, of a bookmarked page, an unbookmarked one and a recent one
+        connection
+            .execute(
+                "CREATE TABLE moz_places (id INTEGER PRIMARY KEY, url TEXT,
+                   foreign_count INTEGER DEFAULT 0, visit_count INTEGER, last_visit_date INTEGER);
+                 CREATE TABLE moz_historyvisits (id INTEGER PRIMARY KEY, place_id INTEGER,
+                   visit_date INTEGER, visit_type INTEGER DEFAULT 1);
+                 CREATE TABLE moz_keywords (id INTEGER PRIMARY KEY, place_id INTEGER);
+                 CREATE TABLE moz_bookmarks (id INTEGER PRIMARY KEY, fk INTEGER);
+                 INSERT INTO moz_places VALUES (1, 'https://bookmarked.example/', 1, 1, 1000000),
+                   (2, 'https://old.example/', 0, 1, 1000000),
+                   (3, 'https://recent.example/', 0, 2, strftime('%s', 'now') * 1000000);
+                 INSERT INTO moz_historyvisits VALUES (1, 1, 1000000, 1), (2, 2, 1000000, 1),
+                   (3, 3, 2000000, 1), (4, 3, strftime('%s', 'now') * 1000000, 1);
+                 INSERT INTO moz_bookmarks VALUES (1, 1);",
+            )
+            .unwrap();
+
+        let known = catalog::find(Path::new("places.sqlite")).unwrap();
+        let retention = Retention {
+            days: Some(30),
+            max_entries: None,
+        };
+        assert_eq!(
+            maintain(&connection, known, &retention, false).unwrap(),
+            vec![
+                Cleanup {
+                    table: "moz_historyvisits",
+                    action: "pruned",
+                    rows: 3
+                },
+                Cleanup {
+                    table: "moz_places",
+                    action: "removed",
+                    rows: 1
+                },
+                Cleanup {
+                    table: "moz_places",
+                    action: "fixed",
+                    rows: 2
+                },
+            ]
+        );
+
+        // Visit counts of the bookmarked page and the recent one are recomputed
+        let mut statement = connection
+            .prepare("SELECT id, visit_count, last_visit_date FROM moz_places ORDER BY id;")
+            .unwrap();
+        let mut places: Vec<(i64, i64, Option<i64>)> = Vec::new();
+        while let sqlite::State::Row = statement.next().unwrap() {
+            places.push((
+                statement.read::<i64, _>(0).unwrap(),
+                statement.read::<i64, _>(1).unwrap(),
+                statement.read::<Option<i64>, _>(2).unwrap(),
+            ));
+        }
+        drop(statement);
+        assert_eq!(places[0], (1, 0, None));
+        assert_eq!((places[1].0, places[1].1), (3, 1));
+        assert!(places[1].2.is_some_and(|date| date > 2000000));
+
+        let retention = Retention {
+            days: None,
+            max_entries: Some(0),
+        };
+        assert_eq!(
+            maintain(&connection, known, &retention, false).unwrap()[0],
+            Cleanup {
+                table: "moz_historyvisits",
+                action: "pruned",
+                rows: 1
+            }
+        );
+    }
+}
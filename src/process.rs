@@ -0,0 +1,149 @@
+use std::{
+    ffi::OsString,
+    fs,
+    os::unix::{ffi::OsStringExt, process::CommandExt},
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use sysinfo::{Pid, ProcessesToUpdate, System};
+use tracing::{debug, error, info};
+
+use crate::defrag::is_running;
+
+/// Interval of checking whether a browser has exited
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Command line of a closed browser to launch it again
+#[derive(Debug)]
+pub struct Launch {
+    pub command: Vec<OsString>,
+    pub cwd: Option<PathBuf>,
+}
+
+/// Wait until every process of a browser exits
+///
+/// `timeout` - Give up after this duration, wait forever if `None`
+pub fn wait_for_exit(browser_name: &str, timeout: Option<Duration>) -> Result<()> {
+    let start = Instant::now();
+    if is_running(browser_name) {
+        info!("Wait for `{browser_name}` to exit");
+    }
+
+    while is_running(browser_name) {
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            bail!(
+                "Cannot defrag. `{browser_name}` is still running after {} seconds",
+                start.elapsed().as_secs()
+            );
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(())
+}
+
+/// Ask a running browser to close with SIGTERM and wait until it exits
+///
+/// The browser saves its session before exiting.
+/// Return command lines of its main processes to launch it again.
+pub fn close(browser_name: &str, timeout: Option<Duration>) -> Result<Vec<Launch>> {
+    let mut launches: Vec<Launch> = Vec::new();
+    for pid in main_processes(browser_name) {
+        let launch = read_launch(pid);
+        info!("Close `{browser_name}` process {pid}");
+        if unsafe { libc::kill(pid.as_u32() as libc::pid_t, libc::SIGTERM) } != 0 {
+            bail!(
+                "Failed to close `{browser_name}` process {pid}: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        match launch {
+            Ok(launch) => launches.push(launch),
+            Err(err) => debug!("Cannot relaunch process {pid}: {err:#}"),
+        }
+    }
+
+    wait_for_exit(browser_name, timeout)?;
+    Ok(launches)
+}
+
+/// Launch closed browser again when dropped, i.e. after defragging whether it succeeds or not
+#[derive(Debug)]
+pub struct Relaunch(pub Vec<Launch>);
+
+impl Drop for Relaunch {
+    fn drop(&mut self) {
+        for launch in self.0.iter() {
+            if let Err(err) = launch.spawn() {
+                error!("{err:#}");
+            }
+        }
+    }
+}
+
+impl Launch {
+    /// Start command line detached from this process
+    fn spawn(&self) -> Result<()> {
+        let Some((program, arguments)) = self.command.split_first() else {
+            return Ok(());
+        };
+
+        let mut command = Command::new(program);
+        command
+            .args(arguments)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0);
+        if let Some(cwd) = self.cwd.as_ref() {
+            command.current_dir(cwd);
+        }
+
+        command
+            .spawn()
+            .with_context(|| format!("Failed to relaunch `{}`", program.to_string_lossy()))?;
+        Ok(())
+    }
+}
+
+/// Processes of a browser which are not started by another process of the browser
+fn main_processes(browser_name: &str) -> Vec<Pid> {
+    let mut sys = System::new();
+    sys.refresh_processes(ProcessesToUpdate::All, true);
+
+    let is_browser = |pid: &Pid| {
+        sys.process(*pid).is_some_and(|p| {
+            p.name()
+                .to_string_lossy()
+                .to_lowercase()
+                .contains(&browser_name.to_lowercase())
+        })
+    };
+
+    sys.processes()
+        .iter()
+        .filter(|(pid, p)| is_browser(pid) && !p.parent().is_some_and(|parent| is_browser(&parent)))
+        .map(|(pid, _)| *pid)
+        .collect()
+}
+
+/// Read command line and working directory of a process from `/proc`
+fn read_launch(pid: Pid) -> Result<Launch> {
+    let path = PathBuf::from(format!("/proc/{pid}/cmdline"));
+    let cmdline =
+        fs::read(&path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    let command: Vec<OsString> = cmdline
+        .split(|&byte| byte == 0)
+        .filter(|argument| !argument.is_empty())
+        .map(|argument| OsString::from_vec(argument.to_vec()))
+        .collect();
+
+    Ok(Launch {
+        command,
+        cwd: fs::read_link(format!("/proc/{pid}/cwd")).ok(),
+    })
+}
@@ -2,19 +2,68 @@ use std::fmt::Write;
 
 use humansize::{format_size_i, BINARY};
 use tabled::{
-    settings::{location::ByColumnName, Alignment, Modify, Style},
+    settings::{location::ByColumnName, Alignment, Modify, Remove, Style},
     Table, Tabled,
 };
 
-use crate::defrag::Browser;
+use crate::{
+    args::{AutoVacuum, Engine},
+    defrag::{Browser, DefragOptions, Status},
+    session::SessionStatus,
+    site_data::SiteDataStatus,
+};
+
+/// Options of a run, columns of the database table are shown only for active ones
+#[derive(Debug, Default)]
+pub struct ReportOptions {
+    /// Engine of the run, used for every vacuumed database, not set in a dry run
+    pub engine: Option<Engine>,
+
+    pub statistics: bool,
+    pub auto_vacuum: bool,
+    pub page_size: bool,
+    pub fts: bool,
+    pub maintenance: bool,
+}
+
+impl ReportOptions {
+    pub fn new(options: &DefragOptions) -> Self {
+        Self {
+            engine: (!options.dry_run).then_some(options.engine),
+            statistics: options.statistics.is_some(),
+            auto_vacuum: options.auto_vacuum.is_some(),
+            page_size: options.page_size.is_some(),
+            fts: options.fts.is_some(),
+            maintenance: options.places_maintenance
+                || options.history_maintenance
+                || options.purge_expired_cookies
+                || options.retention.is_set(),
+        }
+    }
+}
 
 #[derive(Debug, Tabled)]
 struct DatabaseReport {
     #[tabled(rename = "Database")]
     path: String,
 
-    #[tabled(rename = "Defrag")]
-    defrag: String,
+    #[tabled(rename = "Status")]
+    status: String,
+
+    #[tabled(rename = "Statistics")]
+    statistics: String,
+
+    #[tabled(rename = "Auto-vacuum")]
+    auto_vacuum: String,
+
+    #[tabled(rename = "Page size")]
+    page_size: String,
+
+    #[tabled(rename = "FTS")]
+    fts_size: String,
+
+    #[tabled(rename = "Maintenance")]
+    cleanups: String,
 
     #[tabled(rename = "Before")]
     size_before: String,
@@ -29,6 +78,100 @@ struct DatabaseReport {
     percent: String,
 }
 
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Engine::Copy => write!(f, "Copy"),
+            Engine::VacuumInto => write!(f, "VACUUM INTO"),
+            Engine::InPlace => write!(f, "In place"),
+        }
+    }
+}
+
+impl std::fmt::Display for AutoVacuum {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AutoVacuum::None => write!(f, "None"),
+            AutoVacuum::Full => write!(f, "Full"),
+            AutoVacuum::Incremental => write!(f, "Incremental"),
+        }
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Status::NotDefragged => write!(f, "No"),
+            Status::Defragged => write!(f, "Yes"),
+            Status::Corrupted => write!(f, "Corrupted"),
+            Status::CheckFailed => write!(f, "Check failed"),
+            Status::VerifyFailed => write!(f, "Verify failed"),
+            Status::Modified => write!(f, "Modified during defrag"),
+            Status::NoSpace => write!(f, "Not enough space"),
+            Status::PreviouslyDone => write!(f, "Done in previous run"),
+            Status::Salvaged => write!(f, "Salvaged"),
+            Status::Skipped(reason) => write!(f, "Skipped: {reason}"),
+            Status::TimedOut => write!(f, "Timed out"),
+            Status::NotFragmented => write!(f, "Not fragmented"),
+        }
+    }
+}
+
+#[derive(Debug, Tabled)]
+struct SessionReport {
+    #[tabled(rename = "Session file")]
+    path: String,
+
+    #[tabled(rename = "Status")]
+    status: String,
+
+    #[tabled(rename = "Before")]
+    size_before: String,
+
+    #[tabled(rename = "After")]
+    size_after: String,
+
+    #[tabled(rename = "Changed")]
+    changed: String,
+}
+
+#[derive(Debug, Tabled)]
+struct SiteDataReport {
+    #[tabled(rename = "Origin")]
+    origin: String,
+
+    #[tabled(rename = "Site data")]
+    path: String,
+
+    #[tabled(rename = "Removed")]
+    status: String,
+
+    #[tabled(rename = "Size")]
+    size: String,
+}
+
+impl std::fmt::Display for SiteDataStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SiteDataStatus::NotRemoved => write!(f, "No"),
+            SiteDataStatus::Removed => write!(f, "Yes"),
+            SiteDataStatus::Failed => write!(f, "Failed"),
+        }
+    }
+}
+
+impl std::fmt::Display for SessionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SessionStatus::NotCompacted => write!(f, "No"),
+            SessionStatus::Compacted => write!(f, "Compacted"),
+            SessionStatus::Unchanged => write!(f, "Within limits"),
+            SessionStatus::Removed => write!(f, "Removed"),
+            SessionStatus::Failed => write!(f, "Failed"),
+        }
+    }
+}
+
 impl std::fmt::Display for Browser {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         if self.database_lists.is_none() {
@@ -49,16 +192,27 @@ impl std::fmt::Display for Browser {
 
             writeln!(
                 &mut output,
-                "\n{browser_name}: {profile_path}/",
+                "\n{browser_name}: {profile_path}/{filesystem}",
                 browser_name = self.name,
-                profile_path = database_list.path.display()
+                profile_path = database_list.path.display(),
+                filesystem = database_list
+                    .filesystem
+                    .as_ref()
+                    .map_or(String::new(), |name| format!(" ({name})"))
             )?;
+            if let Some(engine) = self.report.engine {
+                writeln!(&mut output, "Engine: {engine}")?;
+            }
 
             // Create table of database files
             let mut db_table: Vec<DatabaseReport> = Vec::new();
             let mut total_before: f64 = 0.0;
             let mut total_after: f64 = 0.0;
             let mut total_changed: f64 = 0.0;
+            // Size before of databases with a size after, e.g. not skipped ones
+            let mut total_compared: f64 = 0.0;
+            // Visits removed by `--keep-history-days` and `--max-history-entries`
+            let mut total_pruned: usize = 0;
             for db in database_list.databases.as_ref().unwrap() {
                 let path: String = db
                     .path
@@ -68,9 +222,55 @@ impl std::fmt::Display for Browser {
                     .unwrap()
                     .to_string();
 
-                let defrag: String = match db.defrag {
-                    true => "Yes".to_string(),
-                    false => "No".to_string(),
+                let status: String = db.status.to_string();
+
+                let statistics: String = if db.statistics_refreshed {
+                    "Refreshed".to_string()
+                } else {
+                    "No".to_string()
+                };
+
+                let auto_vacuum: String = match db.auto_vacuum {
+                    Some((before, after)) if before == after => before.to_string(),
+                    Some((before, after)) => format!("{before} -> {after}"),
+                    None => "N/A".to_string(),
+                };
+
+                let page_size: String = match db.page_size {
+                    Some((before, after)) if before == after => format_size_i(before, BINARY),
+                    Some((before, after)) => format!(
+                        "{} -> {}",
+                        format_size_i(before, BINARY),
+                        format_size_i(after, BINARY)
+                    ),
+                    None => "N/A".to_string(),
+                };
+
+                let fts_size: String = match db.fts_size {
+                    Some((before, after)) if before == after => format_size_i(before, BINARY),
+                    Some((before, after)) => format!(
+                        "{} -> {}",
+                        format_size_i(before, BINARY),
+                        format_size_i(after, BINARY)
+                    ),
+                    None => "N/A".to_string(),
+                };
+
+                let cleanups: String = match db.cleanups.as_ref() {
+                    Some(cleanups) if cleanups.is_empty() => "Nothing".to_string(),
+                    Some(cleanups) => cleanups
+                        .iter()
+                        .inspect(|cleanup| {
+                            if cleanup.action == "pruned" {
+                                total_pruned += cleanup.rows;
+                            }
+                        })
+                        .map(|cleanup| {
+                            format!("{}: {} {}", cleanup.table, cleanup.rows, cleanup.action)
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n"),
+                    None => "N/A".to_string(),
                 };
 
                 let size_before: String = db.size_before.map_or("N/A".to_string(), |s| {
@@ -83,31 +283,33 @@ impl std::fmt::Display for Browser {
                     format_size_i(s, BINARY)
                 });
 
-                let changed: String = {
-                    if db.size_before.is_none() || db.size_after.is_none() {
-                        "N/A".to_string()
-                    } else {
-                        let diff: f64 =
-                            db.size_after.unwrap() as f64 - db.size_before.unwrap() as f64;
+                let changed: String = match (db.size_before, db.size_after) {
+                    (Some(before), Some(after)) => {
+                        let diff: f64 = after as f64 - before as f64;
                         total_changed += diff;
+                        total_compared += before as f64;
                         format_size_i(diff, BINARY)
                     }
+                    _ => "N/A".to_string(),
                 };
 
-                let percent: String = {
-                    if db.size_before.is_none() || db.size_after.is_none() {
-                        "N/A".to_string()
-                    } else {
-                        let diff: f64 =
-                            db.size_after.unwrap() as f64 - db.size_before.unwrap() as f64;
-                        let percent: f64 = (diff * 100.0_f64) / (db.size_before.unwrap() as f64);
+                let percent: String = match (db.size_before, db.size_after) {
+                    (Some(before), Some(after)) => {
+                        let diff: f64 = after as f64 - before as f64;
+                        let percent: f64 = (diff * 100.0_f64) / (before as f64);
                         format!("{percent:.2} %")
                     }
+                    _ => "N/A".to_string(),
                 };
 
                 db_table.push(DatabaseReport {
                     path,
-                    defrag,
+                    status,
+                    statistics,
+                    auto_vacuum,
+                    page_size,
+                    fts_size,
+                    cleanups,
                     size_before,
                     size_after,
                     changed,
@@ -115,30 +317,136 @@ impl std::fmt::Display for Browser {
                 });
             }
 
-            let total_percent: String = {
-                let percent: f64 = (total_after - total_before) * 100.0_f64 / total_before;
+            let total_percent: String = if total_compared > 0.0 {
+                let percent: f64 = total_changed * 100.0_f64 / total_compared;
                 format!("{percent:.2} %")
+            } else {
+                "N/A".to_string()
             };
 
             db_table.push(DatabaseReport {
                 path: "".to_string(),
-                defrag: "".to_string(),
+                status: "".to_string(),
+                statistics: "".to_string(),
+                auto_vacuum: "".to_string(),
+                page_size: "".to_string(),
+                fts_size: "".to_string(),
+                cleanups: "".to_string(),
                 size_before: format_size_i(total_before, BINARY),
                 size_after: format_size_i(total_after, BINARY),
                 changed: format_size_i(total_changed, BINARY),
                 percent: total_percent,
             });
 
+            // Remove columns of inactive options before aligning columns by their names
             let mut table = Table::new(db_table);
+            for (column, shown) in [
+                ("Statistics", self.report.statistics),
+                ("Auto-vacuum", self.report.auto_vacuum),
+                ("Page size", self.report.page_size),
+                ("FTS", self.report.fts),
+                ("Maintenance", self.report.maintenance),
+            ] {
+                if !shown {
+                    table.with(Remove::column(ByColumnName::new(column)));
+                }
+            }
             table
                 .with(Style::markdown())
                 .with(Modify::new(ByColumnName::new("Database")).with(Alignment::left()))
-                .with(Modify::new(ByColumnName::new("Defrag")).with(Alignment::center()))
+                .with(Modify::new(ByColumnName::new("Status")).with(Alignment::center()))
+                .with(Modify::new(ByColumnName::new("Statistics")).with(Alignment::center()))
+                .with(Modify::new(ByColumnName::new("Auto-vacuum")).with(Alignment::center()))
+                .with(Modify::new(ByColumnName::new("Page size")).with(Alignment::center()))
+                .with(Modify::new(ByColumnName::new("FTS")).with(Alignment::right()))
                 .with(Modify::new(ByColumnName::new("Before")).with(Alignment::right()))
                 .with(Modify::new(ByColumnName::new("After")).with(Alignment::right()))
                 .with(Modify::new(ByColumnName::new("Changed")).with(Alignment::right()))
                 .with(Modify::new(ByColumnName::new("Changed %")).with(Alignment::right()));
             writeln!(&mut output, "{table}")?;
+
+            if total_pruned > 0 {
+                writeln!(&mut output, "Visits removed from history: {total_pruned}")?;
+            }
+
+            // Create table of session store files
+            let sessions = database_list.sessions.as_deref().unwrap_or_default();
+            if !sessions.is_empty() {
+                let session_table: Vec<SessionReport> = sessions
+                    .iter()
+                    .map(|session| SessionReport {
+                        path: session
+                            .path
+                            .strip_prefix(&database_list.path)
+                            .unwrap()
+                            .to_str()
+                            .unwrap()
+                            .to_string(),
+                        status: session.status.to_string(),
+                        size_before: session
+                            .size_before
+                            .map_or("N/A".to_string(), |s| format_size_i(s, BINARY)),
+                        size_after: session
+                            .size_after
+                            .map_or("N/A".to_string(), |s| format_size_i(s, BINARY)),
+                        changed: match (session.size_before, session.size_after) {
+                            (Some(before), Some(after)) => {
+                                format_size_i(after as f64 - before as f64, BINARY)
+                            }
+                            _ => "N/A".to_string(),
+                        },
+                    })
+                    .collect();
+
+                let mut table = Table::new(session_table);
+                table
+                    .with(Style::markdown())
+                    .with(Modify::new(ByColumnName::new("Session file")).with(Alignment::left()))
+                    .with(Modify::new(ByColumnName::new("Status")).with(Alignment::center()))
+                    .with(Modify::new(ByColumnName::new("Before")).with(Alignment::right()))
+                    .with(Modify::new(ByColumnName::new("After")).with(Alignment::right()))
+                    .with(Modify::new(ByColumnName::new("Changed")).with(Alignment::right()));
+                writeln!(&mut output, "\n{table}")?;
+            }
+
+            // Create table of stale site data
+            let site_data = database_list.site_data.as_deref().unwrap_or_default();
+            if !site_data.is_empty() {
+                let mut total_size: u64 = 0;
+                let mut site_data_table: Vec<SiteDataReport> = site_data
+                    .iter()
+                    .map(|data| {
+                        total_size += data.size;
+                        SiteDataReport {
+                            origin: data.origin.clone(),
+                            path: data
+                                .path
+                                .strip_prefix(&database_list.path)
+                                .unwrap()
+                                .to_str()
+                                .unwrap()
+                                .to_string(),
+                            status: data.status.to_string(),
+                            size: format_size_i(data.size, BINARY),
+                        }
+                    })
+                    .collect();
+                site_data_table.push(SiteDataReport {
+                    origin: "".to_string(),
+                    path: "".to_string(),
+                    status: "".to_string(),
+                    size: format_size_i(total_size, BINARY),
+                });
+
+                let mut table = Table::new(site_data_table);
+                table
+                    .with(Style::markdown())
+                    .with(Modify::new(ByColumnName::new("Origin")).with(Alignment::left()))
+                    .with(Modify::new(ByColumnName::new("Site data")).with(Alignment::left()))
+                    .with(Modify::new(ByColumnName::new("Removed")).with(Alignment::center()))
+                    .with(Modify::new(ByColumnName::new("Size")).with(Alignment::right()));
+                writeln!(&mut output, "\n{table}")?;
+            }
         }
 
         write!(f, "{output}")
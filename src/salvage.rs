@@ -0,0 +1,355 @@
+use std::{
+    io::{self, BufRead, IsTerminal, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use sqlite::{Connection, OpenFlags, State, Statement, Value};
+use tracing::{debug, warn};
+
+use crate::{catalog::Family, common::quote_identifier, functions};
+
+/// Give up reading a table after this many read errors
+const MAX_READ_ERRORS: u64 = 1024;
+
+/// Result of salvaging a table
+#[derive(Debug)]
+pub struct SalvagedTable {
+    pub name: String,
+
+    /// Number of rows readable from the corrupted database
+    pub rows_read: u64,
+
+    /// Number of rows written to the salvaged database
+    pub rows_written: u64,
+
+    /// Number of unreadable ranges of rows
+    pub read_errors: u64,
+}
+
+impl SalvagedTable {
+    pub fn is_complete(&self) -> bool {
+        self.rows_read == self.rows_written
+    }
+}
+
+/// Rebuild a fresh database at `target` from readable rows of a corrupted database at `source`
+///
+/// Rows of each table are read in rowid order, an unreadable range of rows is skipped
+/// with an increasing step, similar to `.recover` of SQLite's shell.
+/// Indexes, triggers and views are created after all rows are written,
+/// the salvage is incomplete if any of them cannot be created.
+///
+/// `family` - Browser of a known database, whose schema may refer to its SQL functions
+pub fn rebuild(source: &Path, target: &Path, family: Option<Family>) -> Result<Vec<SalvagedTable>> {
+    let source_db = Connection::open_with_flags(source, OpenFlags::new().with_read_only())
+        .with_context(|| format!("Failed to open `{}`", source.display()))?;
+    let target_db =
+        sqlite::open(target).with_context(|| format!("Failed to create `{}`", target.display()))?;
+    if let Some(family) = family {
+        functions::register(&target_db, family)?;
+    }
+
+    // Keep header values of the original
+    for pragma in ["page_size", "auto_vacuum", "user_version", "application_id"] {
+        let mut statement = source_db.prepare(format!("PRAGMA {pragma};"))?;
+        if let State::Row = statement.next()? {
+            let value = statement.read::<i64, _>(0)?;
+            target_db.execute(format!("PRAGMA {pragma} = {value};"))?;
+        }
+    }
+
+    // Schema must be readable, there is nothing to rebuild without it
+    let mut schema: Vec<(String, String, Option<String>)> = Vec::new();
+    source_db
+        .iterate(
+            "SELECT type, name, sql FROM sqlite_schema ORDER BY rowid;",
+            |pairs| {
+                if let [(_, Some(object_type)), (_, Some(name)), (_, sql)] = pairs {
+                    schema.push((
+                        object_type.to_string(),
+                        name.to_string(),
+                        sql.map(|sql| sql.to_string()),
+                    ));
+                }
+                true
+            },
+        )
+        .context("Schema is unreadable")?;
+
+    target_db.execute("BEGIN;")?;
+
+    // Tables, shadow tables of virtual tables may already be created by their virtual table
+    let mut tables: Vec<&str> = Vec::new();
+    for (object_type, name, sql) in schema.iter() {
+        if object_type != "table" || name.starts_with("sqlite_") {
+            continue;
+        }
+        let Some(sql) = sql else {
+            continue;
+        };
+
+        if let Err(err) = target_db.execute(sql) {
+            debug!("Skip creating table `{name}`: {err}");
+        }
+        if !sql.to_uppercase().starts_with("CREATE VIRTUAL TABLE") {
+            tables.push(name);
+        }
+    }
+
+    let mut salvaged: Vec<SalvagedTable> = Vec::new();
+    for table in tables {
+        salvaged.push(copy_rows(&source_db, &target_db, table)?);
+    }
+
+    // AUTOINCREMENT counters
+    if schema.iter().any(|(_, name, _)| name == "sqlite_sequence") {
+        target_db.execute("DELETE FROM sqlite_sequence;")?;
+        salvaged.push(copy_rows(&source_db, &target_db, "sqlite_sequence")?);
+    }
+
+    let mut failed: Vec<String> = Vec::new();
+    for (object_type, name, sql) in schema.iter() {
+        if object_type == "table" {
+            continue;
+        }
+        let Some(sql) = sql else {
+            continue;
+        };
+
+        if let Err(err) = target_db.execute(sql) {
+            warn!("Failed to create {object_type} `{name}` of salvaged database: {err}");
+            failed.push(format!("{object_type} `{name}`"));
+        }
+    }
+    if !failed.is_empty() {
+        bail!(
+            "Salvaged database lacks {}, keep the original",
+            failed.join(", ")
+        );
+    }
+
+    target_db.execute("COMMIT;")?;
+
+    Ok(salvaged)
+}
+
+/// Copy readable rows of a table
+fn copy_rows(source_db: &Connection, target_db: &Connection, table: &str) -> Result<SalvagedTable> {
+    let mut salvaged = SalvagedTable {
+        name: table.to_string(),
+        rows_read: 0,
+        rows_written: 0,
+        read_errors: 0,
+    };
+
+    let has_rowid = source_db
+        .prepare(format!(
+            "SELECT rowid FROM {} LIMIT 0;",
+            quote_identifier(table)
+        ))
+        .is_ok();
+
+    let columns: Vec<String> = match source_db.prepare(format!(
+        "SELECT * FROM {} LIMIT 0;",
+        quote_identifier(table)
+    )) {
+        Ok(statement) => statement.column_names().to_vec(),
+        Err(err) => {
+            warn!("Table `{table}` is unreadable: {err}");
+            salvaged.read_errors += 1;
+            return Ok(salvaged);
+        }
+    };
+
+    let mut column_names: Vec<String> = columns.iter().map(|c| quote_identifier(c)).collect();
+    if has_rowid {
+        column_names.insert(0, "rowid".to_string());
+    }
+    let mut insert = target_db.prepare(format!(
+        "INSERT OR REPLACE INTO {} ({}) VALUES ({});",
+        quote_identifier(table),
+        column_names.join(", "),
+        vec!["?"; column_names.len()].join(", ")
+    ))?;
+
+    if !has_rowid {
+        let mut select =
+            source_db.prepare(format!("SELECT * FROM {};", quote_identifier(table)))?;
+        loop {
+            match select.next() {
+                Ok(State::Row) => {
+                    salvaged.rows_read += 1;
+                    if copy_row(&select, &mut insert, column_names.len()) {
+                        salvaged.rows_written += 1;
+                    }
+                }
+                Ok(State::Done) => break,
+                Err(err) => {
+                    debug!("Stop reading table `{table}`: {err}");
+                    salvaged.read_errors += 1;
+                    break;
+                }
+            }
+        }
+        return Ok(salvaged);
+    }
+
+    // Read in rowid order from the smallest rowid, or 1 if the first rows are unreadable.
+    // Skip an unreadable range with an increasing step.
+    let mut next_rowid: i64 = source_db
+        .prepare(format!(
+            "SELECT min(rowid) FROM {};",
+            quote_identifier(table)
+        ))
+        .and_then(|mut statement| {
+            statement.next()?;
+            statement.read::<Option<i64>, _>(0)
+        })
+        .ok()
+        .flatten()
+        .unwrap_or(1);
+    let mut step: i64 = 1;
+    'read: loop {
+        let mut select = match source_db.prepare(format!(
+            "SELECT rowid, * FROM {} WHERE rowid >= ? ORDER BY rowid;",
+            quote_identifier(table)
+        )) {
+            Ok(select) => select,
+            Err(err) => {
+                debug!("Stop reading table `{table}`: {err}");
+                salvaged.read_errors += 1;
+                break;
+            }
+        };
+        select.bind((1, next_rowid))?;
+
+        loop {
+            match select.next() {
+                Ok(State::Row) => {
+                    salvaged.rows_read += 1;
+                    if copy_row(&select, &mut insert, column_names.len()) {
+                        salvaged.rows_written += 1;
+                    }
+                    let rowid = select.read::<i64, _>(0)?;
+                    match rowid.checked_add(1) {
+                        Some(rowid) => next_rowid = rowid,
+                        None => break 'read,
+                    }
+                    step = 1;
+                }
+                Ok(State::Done) => break 'read,
+                Err(err) => {
+                    debug!(
+                        "Skip unreadable rows of table `{table}` from rowid {next_rowid}: {err}"
+                    );
+                    salvaged.read_errors += 1;
+                    break;
+                }
+            }
+        }
+
+        if salvaged.read_errors >= MAX_READ_ERRORS || next_rowid == i64::MAX {
+            break;
+        }
+        next_rowid = next_rowid.saturating_add(step);
+        step = step.saturating_mul(2);
+    }
+
+    Ok(salvaged)
+}
+
+/// Copy current row of `select` with `insert`, return whether it is written
+fn copy_row(select: &Statement, insert: &mut Statement, column_count: usize) -> bool {
+    let written = (0..column_count)
+        .try_for_each(|column| {
+            let value = select.read::<Value, _>(column)?;
+            bind_value(insert, column + 1, value)
+        })
+        .and_then(|_| insert.next());
+
+    let _ = insert.reset();
+    match written {
+        Ok(_) => true,
+        Err(err) => {
+            debug!("Failed to write a salvaged row: {err}");
+            false
+        }
+    }
+}
+
+fn bind_value(statement: &mut Statement, index: usize, value: Value) -> sqlite::Result<()> {
+    match value {
+        Value::Binary(value) => statement.bind((index, value.as_slice())),
+        Value::Float(value) => statement.bind((index, value)),
+        Value::Integer(value) => statement.bind((index, value)),
+        Value::String(value) => statement.bind((index, value.as_str())),
+        Value::Null => statement.bind((index, ())),
+    }
+}
+
+/// Ask user whether to replace a corrupted database with its salvaged copy
+pub fn confirm(path: &Path, salvaged: &[SalvagedTable]) -> Result<bool> {
+    let mut stderr = io::stderr().lock();
+    writeln!(stderr, "Salvaged database `{}`:", path.display())?;
+    for table in salvaged.iter() {
+        writeln!(
+            stderr,
+            "  {}: {} of {} readable rows, {} unreadable ranges",
+            table.name, table.rows_written, table.rows_read, table.read_errors
+        )?;
+    }
+
+    if !io::stdin().is_terminal() {
+        bail!("Cannot ask for confirmation, standard input is not a terminal");
+    }
+
+    write!(
+        stderr,
+        "Replace `{}` with salvaged database? [y/N] ",
+        path.display()
+    )?;
+    stderr.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_rebuild_with_functions() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source = tmp_dir.path().join("places.sqlite");
+        let connection = sqlite::open(&source).unwrap();
+        functions::register(&connection, Family::Gecko).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE moz_places (id INTEGER PRIMARY KEY, url TEXT);
+                 CREATE INDEX moz_places_url_hashindex ON moz_places (hash(url));
+                 INSERT INTO moz_places (url) VALUES ('https://example.com/');",
+            )
+            .unwrap();
+        drop(connection);
+
+        // Index on `hash()` cannot be created without functions of Gecko
+        let target = tmp_dir.path().join("incomplete.sqlite");
+        assert!(rebuild(&source, &target, None).is_err());
+
+        let target = tmp_dir.path().join("salvaged.sqlite");
+        let salvaged = rebuild(&source, &target, Some(Family::Gecko)).unwrap();
+        assert_eq!(salvaged.len(), 1);
+        assert!(salvaged[0].is_complete());
+
+        let connection = sqlite::open(&target).unwrap();
+        let mut statement = connection
+            .prepare("SELECT count(*) FROM sqlite_schema WHERE name = 'moz_places_url_hashindex'")
+            .unwrap();
+        statement.next().unwrap();
+        assert_eq!(statement.read::<i64, _>(0).unwrap(), 1);
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use tracing::{debug, error, info};
+
+use crate::{
+    backup::Backup,
+    common::{install_staging, staging_path},
+    mozlz4,
+};
+
+/// Session store written by Firefox on shutdown, moved to `previous.jsonlz4` on next startup
+const SESSION_FILE: &str = "sessionstore.jsonlz4";
+
+/// Directory of session store written while Firefox is running and of its backups
+const BACKUP_DIR: &str = "sessionstore-backups";
+
+/// Session store of the previous startup
+const PREVIOUS_FILE: &str = "previous.jsonlz4";
+
+/// Session stores kept when Firefox is upgraded, suffixed with its build ID
+const UPGRADE_PREFIX: &str = "upgrade.jsonlz4-";
+
+/// Limits of history kept in session store, see `--compact-sessions`
+#[derive(Clone, Copy, Debug)]
+pub struct SessionLimits {
+    /// Closed tabs kept in each window
+    pub closed_tabs: usize,
+
+    /// Closed windows kept
+    pub closed_windows: usize,
+
+    /// Back and forward entries kept in history of each tab
+    pub tab_history: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SessionFile {
+    pub path: PathBuf,
+    pub size_before: Option<u64>,
+    pub size_after: Option<u64>,
+    pub status: SessionStatus,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub enum SessionStatus {
+    /// Session file has not been compacted
+    #[default]
+    NotCompacted,
+
+    /// Closed tabs, closed windows or tab history have been trimmed
+    Compacted,
+
+    /// Nothing is beyond limits
+    Unchanged,
+
+    /// Stale backup has been removed
+    Removed,
+
+    /// Session file could not be read or written
+    Failed,
+}
+
+/// Compact session store files of a profile, and remove its stale backups
+///
+/// `dry_run` - Only list session files.
+pub fn compact(
+    profile: &Path,
+    limits: &SessionLimits,
+    dry_run: bool,
+    backup: Option<&Backup>,
+) -> Vec<SessionFile> {
+    let stale: Vec<PathBuf> = find_stale_backups(profile, backup.is_some());
+    let mut sessions: Vec<SessionFile> = Vec::new();
+    for path in find_session_files(profile) {
+        let mut session = SessionFile {
+            size_before: path.metadata().map(|metadata| metadata.len()).ok(),
+            size_after: None,
+            status: SessionStatus::default(),
+            path,
+        };
+
+        if dry_run {
+            session.size_after = session.size_before;
+            sessions.push(session);
+            continue;
+        }
+
+        let result = if stale.contains(&session.path) {
+            remove(&session.path, backup).map(|_| {
+                session.size_after = Some(0);
+                SessionStatus::Removed
+            })
+        } else {
+            compact_file(&session.path, limits, backup).and_then(|compacted| {
+                session.size_after = Some(session.path.metadata()?.len());
+                Ok(if compacted {
+                    SessionStatus::Compacted
+                } else {
+                    SessionStatus::Unchanged
+                })
+            })
+        };
+
+        session.status = match result {
+            Ok(status) => status,
+            Err(err) => {
+                error!("{err:#}");
+                SessionStatus::Failed
+            }
+        };
+        sessions.push(session);
+    }
+
+    sessions
+}
+
+/// Find session store files of a profile, they do not exist in profiles of other browsers
+fn find_session_files(profile: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+
+    let session = profile.join(SESSION_FILE);
+    if session.is_file() {
+        paths.push(session);
+    }
+
+    if let Ok(entries) = fs::read_dir(profile.join(BACKUP_DIR)) {
+        let mut backups: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .filter(|path| {
+                path.file_name().is_some_and(|name| {
+                    let name = name.to_string_lossy();
+                    name.ends_with(".jsonlz4")
+                        || name.ends_with(".baklz4")
+                        || name.starts_with(UPGRADE_PREFIX)
+                })
+            })
+            .collect();
+        backups.sort();
+        paths.append(&mut backups);
+    }
+
+    paths
+}
+
+/// Find backups which Firefox does not need anymore
+///
+/// - Upgrade backups except the latest one
+/// - `previous.jsonlz4` if it is older than `sessionstore.jsonlz4`, which replaces it on next
+///   startup, and it is kept in a backup. It is the only copy of the session if Firefox fails to
+///   restore the current one.
+///
+/// `backed_up` - Removed files are kept in a backup, see `--backup`.
+fn find_stale_backups(profile: &Path, backed_up: bool) -> Vec<PathBuf> {
+    let backup_dir = profile.join(BACKUP_DIR);
+    let mut stale: Vec<PathBuf> = Vec::new();
+
+    let previous = backup_dir.join(PREVIOUS_FILE);
+    let modified = |path: &Path| {
+        path.metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    if backed_up
+        && modified(&previous)
+            .zip(modified(&profile.join(SESSION_FILE)))
+            .is_some_and(|(previous, session)| previous < session)
+    {
+        stale.push(previous);
+    }
+
+    let Ok(entries) = fs::read_dir(&backup_dir) else {
+        return stale;
+    };
+    let mut upgrades: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(UPGRADE_PREFIX)
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    upgrades.sort();
+    upgrades.pop();
+    stale.extend(upgrades.into_iter().map(|(_, path)| path));
+
+    stale
+}
+
+/// Remove a stale backup
+fn remove(path: &Path, backup: Option<&Backup>) -> Result<()> {
+    if let Some(backup) = backup {
+        backup.save(path)?;
+    }
+
+    fs::remove_file(path).with_context(|| format!("Failed to remove `{}`", path.display()))?;
+    info!("Removed stale session backup `{}`", path.display());
+    Ok(())
+}
+
+/// Trim a session store file, it is only written if anything is beyond limits
+fn compact_file(path: &Path, limits: &SessionLimits, backup: Option<&Backup>) -> Result<bool> {
+    let data = fs::read(path).with_context(|| format!("Could not read `{}`", path.display()))?;
+    let mut session: Value = mozlz4::decompress(&data)
+        .and_then(|json| Ok(serde_json::from_slice(&json)?))
+        .with_context(|| format!("Invalid session store `{}`", path.display()))?;
+
+    if !trim_session(&mut session, limits) {
+        debug!("Session store `{}` is within limits", path.display());
+        return Ok(false);
+    }
+
+    let compressed = mozlz4::compress(&serde_json::to_vec(&session)?)?;
+    if let Some(backup) = backup {
+        backup.save(path)?;
+    }
+
+    let staging = staging_path(path);
+    fs::write(&staging, compressed)
+        .with_context(|| format!("Failed to write `{}`", staging.display()))?;
+    install_staging(path)?;
+
+    Ok(true)
+}
+
+/// Trim closed windows, closed tabs and tab history of a session, return whether it changed
+fn trim_session(session: &mut Value, limits: &SessionLimits) -> bool {
+    let mut changed = false;
+
+    if let Some(windows) = session.get_mut("windows").and_then(Value::as_array_mut) {
+        for window in windows {
+            changed |= trim_window(window, limits);
+        }
+    }
+
+    // Most recently closed windows are first
+    if let Some(windows) = session
+        .get_mut("_closedWindows")
+        .and_then(Value::as_array_mut)
+    {
+        changed |= truncate(windows, limits.closed_windows);
+        for window in windows {
+            changed |= trim_window(window, limits);
+        }
+    }
+
+    changed
+}
+
+/// Trim closed tabs and history of tabs of a window
+fn trim_window(window: &mut Value, limits: &SessionLimits) -> bool {
+    let mut changed = false;
+
+    if let Some(tabs) = window.get_mut("tabs").and_then(Value::as_array_mut) {
+        for tab in tabs {
+            changed |= trim_tab_history(tab, limits.tab_history);
+        }
+    }
+
+    // Most recently closed tabs are first, their tab is in `state`
+    if let Some(tabs) = window.get_mut("_closedTabs").and_then(Value::as_array_mut) {
+        changed |= truncate(tabs, limits.closed_tabs);
+        for tab in tabs {
+            if let Some(state) = tab.get_mut("state") {
+                changed |= trim_tab_history(state, limits.tab_history);
+            }
+        }
+    }
+
+    changed
+}
+
+/// Keep `max` back and `max` forward entries around the current entry of a tab
+///
+/// `index` of a tab is 1-based, the last entry is current if it is missing.
+fn trim_tab_history(tab: &mut Value, max: usize) -> bool {
+    let Some(entries) = tab.get("entries").and_then(Value::as_array) else {
+        return false;
+    };
+    if entries.is_empty() {
+        return false;
+    }
+
+    let current: usize = tab
+        .get("index")
+        .and_then(Value::as_u64)
+        .map_or(entries.len(), |index| index as usize)
+        .clamp(1, entries.len())
+        - 1;
+    let start: usize = current.saturating_sub(max);
+    let end: usize = (current + max + 1).min(entries.len());
+    if start == 0 && end == entries.len() {
+        return false;
+    }
+
+    let kept: Vec<Value> = entries[start..end].to_vec();
+    tab["entries"] = Value::Array(kept);
+    tab["index"] = Value::from(current - start + 1);
+    true
+}
+
+/// Keep the first `max` items, return whether any is removed
+fn truncate(items: &mut Vec<Value>, max: usize) -> bool {
+    let changed = items.len() > max;
+    items.truncate(max);
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_trim_session() {
+        let entries = |count: usize| -> Vec<Value> {
+            (1..=count)
+                .map(|i| json!({ "url": format!("https://example.com/{i}") }))
+                .collect()
+        };
+        let mut session = json!({
+            "windows": [{
+                "tabs": [
+                    { "entries": entries(5), "index": 3 },
+                    { "entries": entries(2), "index": 2 },
+                ],
+                "_closedTabs": [
+                    { "state": { "entries": entries(4) } },
+                    { "state": { "entries": entries(1) } },
+                    { "state": { "entries": entries(1) } },
+                ],
+            }],
+            "_closedWindows": [{ "tabs": [] }, { "tabs": [] }],
+        });
+
+        let limits = SessionLimits {
+            closed_tabs: 2,
+            closed_windows: 1,
+            tab_history: 1,
+        };
+        assert!(trim_session(&mut session, &limits));
+
+        let window = &session["windows"][0];
+        assert_eq!(window["tabs"][0]["entries"], json!(entries(4)[1..4]));
+        assert_eq!(window["tabs"][0]["index"], 2);
+        assert_eq!(window["tabs"][1]["entries"], json!(entries(2)));
+        assert_eq!(window["_closedTabs"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            window["_closedTabs"][0]["state"]["entries"],
+            json!(entries(4)[2..4])
+        );
+        assert_eq!(window["_closedTabs"][0]["state"]["index"], 2);
+        assert_eq!(session["_closedWindows"].as_array().unwrap().len(), 1);
+
+        assert!(!trim_session(&mut session, &limits));
+
+        // Keys keep their order, so the rewritten file only differs in trimmed lists
+        assert_eq!(
+            session.as_object().unwrap().keys().collect::<Vec<_>>(),
+            vec!["windows", "_closedWindows"]
+        );
+    }
+
+    #[test]
+    fn test_find_stale_backups() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let profile = tmp_dir.path();
+        fs::create_dir(profile.join(BACKUP_DIR)).unwrap();
+        let previous = profile.join(BACKUP_DIR).join(PREVIOUS_FILE);
+        let session = profile.join(SESSION_FILE);
+        let set_modified = |path: &Path, seconds: u64| {
+            fs::File::create(path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+                .unwrap();
+        };
+
+        set_modified(&previous, 1000);
+        set_modified(&session, 2000);
+        assert_eq!(find_stale_backups(profile, true), vec![previous.clone()]);
+        assert_eq!(find_stale_backups(profile, false), Vec::<PathBuf>::new());
+
+        // Session store was not written after Firefox started, e.g. it crashed
+        set_modified(&session, 500);
+        assert_eq!(find_stale_backups(profile, true), Vec::<PathBuf>::new());
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use sqlite::{Connection, OpenFlags, State};
+use tracing::{debug, error, info};
+use walkdir::WalkDir;
+
+use crate::catalog::Family;
+
+/// Seconds between 1601-01-01 (WebKit epoch) and 1970-01-01 (Unix epoch)
+const WEBKIT_EPOCH_OFFSET: i64 = 11644473600;
+
+/// Data of an origin, only stale ones are listed, see `--purge-stale-site-data`
+#[derive(Debug, PartialEq)]
+pub struct SiteData {
+    /// Origin, e.g. `https://example.com`
+    pub origin: String,
+
+    /// Directory of data
+    pub path: PathBuf,
+
+    /// Total size of files in bytes
+    pub size: u64,
+
+    pub status: SiteDataStatus,
+}
+
+/// Origins allowed to persist their storage
+#[derive(Debug, Default)]
+struct Persistent {
+    /// Origins, e.g. `https://example.com`
+    origins: HashSet<String>,
+
+    /// Origins whose subdomains are allowed too, from Chromium's patterns like `https://[*.]example.com`
+    domains: HashSet<String>,
+}
+
+impl Persistent {
+    fn contains(&self, origin: &str) -> bool {
+        self.origins.contains(origin)
+            || self
+                .domains
+                .iter()
+                .any(|domain| is_same_site(origin, domain))
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub enum SiteDataStatus {
+    /// Data has not been removed
+    #[default]
+    NotRemoved,
+
+    /// Data has been removed
+    Removed,
+
+    /// Data could not be removed
+    Failed,
+}
+
+/// Remove data of origins which are neither visited nor touched within `days`
+///
+/// `history` - `places.sqlite` or `History`, data is in the browser profile next to it.
+/// Origins with persistent storage and origins of other schemes than HTTP(S), e.g. extensions,
+/// are kept.
+///
+/// `dry_run` - Only list stale data.
+pub fn purge_stale(
+    history: &Path,
+    family: Family,
+    days: u32,
+    dry_run: bool,
+) -> Result<Vec<SiteData>> {
+    let profile = history.parent().unwrap();
+    let cutoff: i64 = (SystemTime::now() - Duration::from_secs(days as u64 * 86400))
+        .duration_since(UNIX_EPOCH)?
+        .as_secs() as i64;
+
+    let last_visits = read_last_visits(history, family)
+        .with_context(|| format!("Failed to read last visits of `{}`", history.display()))?;
+    let persistent = match family {
+        Family::Gecko => read_gecko_persistent(&profile.join("permissions.sqlite")),
+        Family::Chromium => read_chromium_persistent(&profile.join("Preferences")),
+    }
+    .with_context(|| {
+        format!(
+            "Failed to read persistent storage permissions of `{}`",
+            profile.display()
+        )
+    })?;
+
+    let mut stale: Vec<SiteData> = Vec::new();
+    for (origin, path) in list_site_data(profile, family) {
+        if persistent.contains(&origin)
+            || last_visits
+                .get(&origin)
+                .is_some_and(|&visited| visited >= cutoff)
+        {
+            continue;
+        }
+
+        // Data of a site which is embedded in others, or visited in a private window
+        let (size, modified) = measure(&path);
+        if modified >= cutoff {
+            continue;
+        }
+
+        debug!("Data of origin `{origin}` is stale: `{}`", path.display());
+        stale.push(SiteData {
+            origin,
+            path,
+            size,
+            status: SiteDataStatus::default(),
+        });
+    }
+    stale.sort_by(|a, b| a.origin.cmp(&b.origin).then(a.path.cmp(&b.path)));
+
+    if dry_run || stale.is_empty() {
+        return Ok(stale);
+    }
+
+    for data in stale.iter_mut() {
+        data.status = match fs::remove_dir_all(&data.path) {
+            Ok(()) => {
+                info!("Removed data of origin `{}`", data.origin);
+                SiteDataStatus::Removed
+            }
+            Err(err) => {
+                error!("Failed to remove `{}`: {err}", data.path.display());
+                SiteDataStatus::Failed
+            }
+        };
+    }
+
+    if family == Family::Gecko {
+        invalidate_gecko_storage_cache(&profile.join("storage.sqlite"))?;
+    }
+
+    Ok(stale)
+}
+
+/// Last visit of each origin, in seconds since Unix epoch
+fn read_last_visits(history: &Path, family: Family) -> Result<HashMap<String, i64>> {
+    let sql = match family {
+        Family::Gecko => {
+            "SELECT url, last_visit_date / 1000000 FROM moz_places
+             WHERE last_visit_date NOT NULL;"
+        }
+        Family::Chromium => {
+            "SELECT url, last_visit_time / 1000000 FROM urls WHERE last_visit_time <> 0;"
+        }
+    };
+
+    let connection = Connection::open_with_flags(history, OpenFlags::new().with_read_only())?;
+    let mut statement = connection.prepare(sql)?;
+    let mut last_visits: HashMap<String, i64> = HashMap::new();
+    while let State::Row = statement.next()? {
+        let Some(origin) = url_origin(&statement.read::<String, _>(0)?) else {
+            continue;
+        };
+        let mut visited = statement.read::<i64, _>(1)?;
+        if family == Family::Chromium {
+            visited -= WEBKIT_EPOCH_OFFSET;
+        }
+
+        let last_visit = last_visits.entry(origin).or_insert(visited);
+        *last_visit = (*last_visit).max(visited);
+    }
+
+    Ok(last_visits)
+}
+
+/// Origins allowed to persist their storage in Firefox's `permissions.sqlite`
+fn read_gecko_persistent(permissions: &Path) -> Result<Persistent> {
+    let mut persistent = Persistent::default();
+    if !permissions.exists() {
+        return Ok(persistent);
+    }
+
+    let connection = Connection::open_with_flags(permissions, OpenFlags::new().with_read_only())?;
+    let mut statement = connection.prepare(
+        "SELECT origin FROM moz_perms WHERE type = 'persistent-storage' AND permission = 1;",
+    )?;
+    while let State::Row = statement.next()? {
+        // Origin attributes, e.g. `^userContextId=1`, are ignored
+        let origin = statement.read::<String, _>(0)?;
+        let origin = origin
+            .split_once('^')
+            .map_or(origin.as_str(), |(origin, _)| origin);
+        if let Some(origin) = url_origin(origin) {
+            persistent.origins.insert(origin);
+        }
+    }
+
+    Ok(persistent)
+}
+
+/// Origins allowed to persist their storage in Chromium's `Preferences`
+///
+/// Exceptions of `durable_storage` are patterns like `https://[*.]example.com:443,*`,
+/// where `[*.]` also allows subdomains.
+fn read_chromium_persistent(preferences: &Path) -> Result<Persistent> {
+    let mut persistent = Persistent::default();
+    if !preferences.exists() {
+        return Ok(persistent);
+    }
+
+    let preferences: Value = serde_json::from_slice(&fs::read(preferences)?)?;
+    if let Some(exceptions) = preferences
+        .pointer("/profile/content_settings/exceptions/durable_storage")
+        .and_then(Value::as_object)
+    {
+        for (pattern, exception) in exceptions {
+            if exception.get("setting").and_then(Value::as_i64) != Some(1) {
+                continue;
+            }
+
+            let pattern = pattern
+                .split_once(',')
+                .map_or(pattern.as_str(), |(primary, _)| primary);
+            let Some(origin) = url_origin(&pattern.replace("[*.]", "")) else {
+                continue;
+            };
+            if pattern.contains("[*.]") {
+                persistent.domains.insert(origin);
+            } else {
+                persistent.origins.insert(origin);
+            }
+        }
+    }
+
+    Ok(persistent)
+}
+
+/// Directories of site data and their origins, directories of other schemes are not listed
+///
+/// - Firefox: `storage/default/https+++example.com+8443^userContextId=1`
+/// - Chromium: `IndexedDB/https_example.com_0.indexeddb.leveldb`, port 0 is the default one
+///
+/// Chromium's `Local Storage` is a single LevelDB of all origins, which is not changed.
+fn list_site_data(profile: &Path, family: Family) -> Vec<(String, PathBuf)> {
+    let root = match family {
+        Family::Gecko => profile.join("storage").join("default"),
+        Family::Chromium => profile.join("IndexedDB"),
+    };
+
+    let Ok(entries) = fs::read_dir(&root) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let origin = match family {
+                Family::Gecko => gecko_origin(name),
+                Family::Chromium => chromium_origin(name),
+            }?;
+            Some((origin, path))
+        })
+        .collect()
+}
+
+/// Total size of files in a directory, and their latest modification in seconds since Unix epoch
+fn measure(path: &Path) -> (u64, i64) {
+    let mut size: u64 = 0;
+    let mut modified: i64 = 0;
+    for metadata in WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+    {
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+        if let Some(time) = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        {
+            modified = modified.max(time.as_secs() as i64);
+        }
+    }
+
+    (size, modified)
+}
+
+/// Make Firefox rescan its storage directory on next startup, instead of its cache of origins
+fn invalidate_gecko_storage_cache(storage: &Path) -> Result<()> {
+    if !storage.exists() {
+        return Ok(());
+    }
+
+    let connection = sqlite::open(storage)?;
+    let mut statement =
+        connection.prepare("SELECT name FROM pragma_table_info('cache') WHERE name = 'valid';")?;
+    if let State::Row = statement.next()? {
+        drop(statement);
+        connection
+            .execute("UPDATE cache SET valid = 0;")
+            .with_context(|| format!("Failed to invalidate cache of `{}`", storage.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Origin of a HTTP(S) URL
+fn url_origin(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    origin(&scheme.to_ascii_lowercase(), host)
+}
+
+/// Origin of a directory of Firefox's `storage/default`
+fn gecko_origin(name: &str) -> Option<String> {
+    let name = name.split('^').next()?;
+    let (scheme, host) = name.split_once("+++")?;
+    origin(scheme, &host.replace('+', ":"))
+}
+
+/// Origin of a directory of Chromium's `IndexedDB`
+fn chromium_origin(name: &str) -> Option<String> {
+    let name = name
+        .strip_suffix(".indexeddb.leveldb")
+        .or_else(|| name.strip_suffix(".indexeddb.blob"))?;
+    let (scheme_host, port) = name.rsplit_once('_')?;
+    let (scheme, host) = scheme_host.split_once('_')?;
+    match port {
+        "0" => origin(scheme, host),
+        port => origin(scheme, &format!("{host}:{port}")),
+    }
+}
+
+/// Whether `origin` is `domain` or one of its subdomains, with the same scheme and port
+fn is_same_site(origin: &str, domain: &str) -> bool {
+    let Some((scheme, host)) = domain.split_once("://") else {
+        return false;
+    };
+    let Some((origin_scheme, origin_host)) = origin.split_once("://") else {
+        return false;
+    };
+
+    origin_scheme == scheme
+        && (origin_host == host
+            || origin_host
+                .strip_suffix(host)
+                .is_some_and(|subdomain| subdomain.ends_with('.')))
+}
+
+/// Origin of HTTP(S) without its default port, other schemes have no origin
+fn origin(scheme: &str, host: &str) -> Option<String> {
+    let default_port = match scheme {
+        "http" => ":80",
+        "https" => ":443",
+        _ => return None,
+    };
+
+    let host = host.to_ascii_lowercase();
+    if host.is_empty() {
+        return None;
+    }
+    let host = host.strip_suffix(default_port).unwrap_or(&host);
+    Some(format!("{scheme}://{host}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_origins() {
+        assert_eq!(
+            url_origin("HTTPS://user@Example.com:443/path?query#fragment"),
+            Some("https://example.com".to_string())
+        );
+        assert_eq!(url_origin("moz-extension://uuid/page.html"), None);
+
+        assert_eq!(
+            gecko_origin("https+++example.com+8443^userContextId=1"),
+            Some("https://example.com:8443".to_string())
+        );
+        assert_eq!(
+            gecko_origin("http+++[++1]+8080"),
+            Some("http://[::1]:8080".to_string())
+        );
+        assert_eq!(gecko_origin("moz-extension+++uuid"), None);
+
+        assert_eq!(
+            chromium_origin("https_example.com_0.indexeddb.leveldb"),
+            Some("https://example.com".to_string())
+        );
+        assert_eq!(
+            chromium_origin("http_localhost_8080.indexeddb.blob"),
+            Some("http://localhost:8080".to_string())
+        );
+        assert_eq!(
+            chromium_origin("chrome-extension_id_0.indexeddb.leveldb"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_read_chromium_persistent() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let preferences = tmp_dir.path().join("Preferences");
+        fs::write(
+            &preferences,
+            r#"{"profile": {"content_settings": {"exceptions": {"durable_storage": {
+                "https://[*.]example.com:443,*": {"setting": 1},
+                "https://example.org:443,*": {"setting": 1},
+                "https://[*.]example.net:443,*": {"setting": 2}
+            }}}}}"#,
+        )
+        .unwrap();
+
+        let persistent = read_chromium_persistent(&preferences).unwrap();
+        assert!(persistent.contains("https://example.com"));
+        assert!(persistent.contains("https://mail.example.com"));
+        assert!(!persistent.contains("http://mail.example.com"));
+        assert!(!persistent.contains("https://notexample.com"));
+        assert!(!persistent.contains("https://example.com:8443"));
+        assert!(persistent.contains("https://example.org"));
+        assert!(!persistent.contains("https://mail.example.org"));
+        assert!(!persistent.contains("https://example.net"));
+    }
+}
@@ -0,0 +1,33 @@
+use std::{
+    ffi::{c_int, c_void},
+    time::{Duration, Instant},
+};
+
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+
+/// Number of virtual machine instructions between checks of a deadline
+const PROGRESS_INTERVAL: c_int = 10_000;
+
+/// How long to wait for a lock held by another connection
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interrupt statements of a connection running after a deadline
+///
+/// # Safety
+///
+/// `deadline` must outlive `connection`.
+pub unsafe fn set_deadline(connection: &Connection, deadline: &Instant) {
+    ffi::sqlite3_progress_handler(
+        connection.as_raw(),
+        PROGRESS_INTERVAL,
+        Some(check_deadline),
+        deadline as *const Instant as *mut c_void,
+    );
+}
+
+/// Progress handler, a non-zero result interrupts the running statement
+unsafe extern "C" fn check_deadline(deadline: *mut c_void) -> c_int {
+    let deadline = &*(deadline as *const Instant);
+    c_int::from(Instant::now() >= *deadline)
+}